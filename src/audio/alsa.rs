@@ -0,0 +1,380 @@
+//! Talks to the ALSA simple mixer directly, for systems without a sound
+//! server. Each card's Master, PCM and Capture controls are listed.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use alsa::{
+    card,
+    mixer::{Mixer, Selem, SelemChannelId, SelemId},
+    poll::Descriptors,
+};
+
+use super::{
+    handle_change_event,
+    shared_output_list::{self, set_default_output, OutputId, VolumeType},
+    Audio,
+};
+use crate::{
+    exception::Exception,
+    options::{Backend, OPTIONS},
+};
+
+/// Simple mixer controls that are shown, and whether they play back.
+const CONTROLS: [(&str, bool); 3] = [("Master", true), ("PCM", true), ("Capture", false)];
+
+/// How often the watcher checks whether it should stop.
+const POLL_TIMEOUT_MS: i32 = 500;
+
+type SharedOutputs = Arc<Mutex<Vec<shared_output_list::Output>>>;
+/// Mixers by card index.
+type CardMixers = Vec<(i32, Mixer)>;
+
+pub struct Alsa {
+    /// Used for setting volumes. The watcher thread has its own handles.
+    mixers: Mutex<CardMixers>,
+    controls: Vec<Control>,
+    outputs: SharedOutputs,
+    quit: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone)]
+struct Control {
+    card: i32,
+    card_name: String,
+    name: &'static str,
+    playback: bool,
+}
+
+impl Alsa {
+    pub fn new() -> Result<Alsa, Exception> {
+        let mixers = open_mixers()?;
+        let controls = find_controls(&mixers);
+
+        if controls.is_empty() {
+            return Err(Exception::Misc("No ALSA mixer controls found.".to_string()));
+        }
+
+        let outputs: SharedOutputs = Arc::new(Mutex::new(read_outputs(&mixers, &controls)));
+
+        if let Some(name) = outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|output| matches!(output.id.type_, VolumeType::Sink))
+            .and_then(|output| output.id.name.clone())
+        {
+            // ALSA has no default device, so the first playback control is used.
+            set_default_output(name);
+        }
+
+        let quit = Arc::new(AtomicBool::new(false));
+
+        let watcher_mixers = open_mixers()?;
+        let thread = thread::spawn({
+            let controls = controls.clone();
+            let outputs = outputs.clone();
+            let quit = quit.clone();
+            move || watch(watcher_mixers, controls, outputs, quit)
+        });
+
+        Ok(Alsa {
+            mixers: Mutex::new(mixers),
+            controls,
+            outputs,
+            quit,
+            thread: Some(thread),
+        })
+    }
+
+    fn with_selem(&self, output_id: &OutputId, f: impl FnOnce(&Selem, &Control)) {
+        let control = match self
+            .controls
+            .iter()
+            .find(|control| control.id() == *output_id)
+        {
+            Some(control) => control,
+            None => return,
+        };
+
+        let mixers = self.mixers.lock().unwrap();
+        let mixer = match mixers.iter().find(|(card, _)| *card == control.card) {
+            Some((_, mixer)) => mixer,
+            None => return,
+        };
+
+        // Nothing is read from this handle, but its events still queue up.
+        let _ = mixer.handle_events();
+
+        if let Some(selem) = mixer.find_selem(&SelemId::new(control.name, 0)) {
+            f(&selem, control);
+        }
+    }
+}
+
+impl Audio for Alsa {
+    fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>) {
+        let outputs = self
+            .outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|output| match output.id.type_ {
+                VolumeType::Input => OPTIONS.show_inputs,
+                _ => true,
+            })
+            .cloned()
+            .collect();
+
+        after(outputs);
+    }
+
+    fn set_volume(&self, sink_id: OutputId, volume: f32) {
+        self.with_selem(&sink_id, |selem, control| {
+            let result = if control.playback {
+                let raw = percent_to_raw(volume, selem.get_playback_volume_range());
+                selem.set_playback_volume_all(raw)
+            } else {
+                let raw = percent_to_raw(volume, selem.get_capture_volume_range());
+                selem.set_capture_volume_all(raw)
+            };
+
+            if let Err(e) = result {
+                alsa_error(e).log_and_ignore();
+            }
+        });
+    }
+
+    fn set_muted(&self, sink_id: OutputId, muted: bool) {
+        self.with_selem(&sink_id, |selem, control| {
+            // The switch is on when the control is unmuted.
+            let switch = if muted { 0 } else { 1 };
+
+            let result = if control.playback && selem.has_playback_switch() {
+                selem.set_playback_switch_all(switch)
+            } else if !control.playback && selem.has_capture_switch() {
+                selem.set_capture_switch_all(switch)
+            } else {
+                return;
+            };
+
+            if let Err(e) = result {
+                alsa_error(e).log_and_ignore();
+            }
+        });
+    }
+
+    fn cleanup(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.quit.store(true, Ordering::Relaxed);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Alsa {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+impl Control {
+    fn id(&self) -> OutputId {
+        let type_ = if self.playback {
+            VolumeType::Sink
+        } else {
+            VolumeType::Input
+        };
+        OutputId::new(
+            Backend::Alsa,
+            type_,
+            None,
+            Some(control_id(self.card, self.name)),
+        )
+    }
+}
+
+/// Waits on the mixers' poll descriptors and reports any changes.
+fn watch(
+    mixers: CardMixers,
+    controls: Vec<Control>,
+    outputs: SharedOutputs,
+    quit: Arc<AtomicBool>,
+) {
+    let descriptors: Vec<&dyn Descriptors> = mixers.iter().map(|(_, m)| m as _).collect();
+
+    while !quit.load(Ordering::Relaxed) {
+        match alsa::poll::poll_all(&descriptors, POLL_TIMEOUT_MS) {
+            Ok(ready) if ready.is_empty() => continue,
+            Ok(_) => {}
+            Err(e) => {
+                alsa_error(e).log_and_ignore();
+                return;
+            }
+        }
+
+        for (_, mixer) in mixers.iter() {
+            let _ = mixer.handle_events();
+        }
+
+        let new_outputs = read_outputs(&mixers, &controls);
+        let mut old_outputs = outputs.lock().unwrap();
+        let changed = new_outputs.len() != old_outputs.len()
+            || old_outputs
+                .iter()
+                .zip(new_outputs.iter())
+                .any(|(old, new)| old.volume != new.volume || old.muted != new.muted);
+
+        if changed {
+            *old_outputs = new_outputs;
+            drop(old_outputs);
+            handle_change_event();
+        }
+    }
+}
+
+fn open_mixers() -> Result<CardMixers, Exception> {
+    let mut mixers = vec![];
+
+    for card in card::Iter::new() {
+        let card = card.map_err(alsa_error)?;
+        let index = card.get_index();
+
+        match Mixer::new(&format!("hw:{}", index), true) {
+            Ok(mixer) => mixers.push((index, mixer)),
+            Err(e) => alsa_error(e).log_and_ignore(),
+        }
+    }
+
+    Ok(mixers)
+}
+
+fn find_controls(mixers: &CardMixers) -> Vec<Control> {
+    let mut controls = vec![];
+
+    for (card, mixer) in mixers {
+        let card_name = card::Card::new(*card)
+            .get_name()
+            .unwrap_or_else(|_| format!("Card {}", card));
+
+        for (name, playback) in CONTROLS {
+            let selem = match mixer.find_selem(&SelemId::new(name, 0)) {
+                Some(selem) => selem,
+                None => continue,
+            };
+
+            let has_volume = if playback {
+                selem.has_playback_volume()
+            } else {
+                selem.has_capture_volume()
+            };
+
+            if has_volume {
+                controls.push(Control {
+                    card: *card,
+                    card_name: card_name.clone(),
+                    name,
+                    playback,
+                });
+            }
+        }
+    }
+
+    controls
+}
+
+fn read_outputs(mixers: &CardMixers, controls: &[Control]) -> Vec<shared_output_list::Output> {
+    controls
+        .iter()
+        .filter_map(|control| {
+            let (_, mixer) = mixers.iter().find(|(card, _)| *card == control.card)?;
+            read_output(mixer, control)
+        })
+        .collect()
+}
+
+fn read_output(mixer: &Mixer, control: &Control) -> Option<shared_output_list::Output> {
+    let selem = mixer.find_selem(&SelemId::new(control.name, 0))?;
+    let channel = SelemChannelId::mono();
+
+    let (volume, muted) = if control.playback {
+        let raw = selem.get_playback_volume(channel).ok()?;
+        let switch = selem.has_playback_switch() && selem.get_playback_switch(channel).ok()? == 0;
+        (
+            raw_to_percent(raw, selem.get_playback_volume_range()),
+            switch,
+        )
+    } else {
+        let raw = selem.get_capture_volume(channel).ok()?;
+        let switch = selem.has_capture_switch() && selem.get_capture_switch(channel).ok()? == 0;
+        (
+            raw_to_percent(raw, selem.get_capture_volume_range()),
+            switch,
+        )
+    };
+
+    Some(shared_output_list::Output {
+        name: format!("{}: {}", control.card_name, control.name),
+        volume,
+        muted,
+        id: control.id(),
+        icon_name: None,
+        sink_id: None,
+        channels: vec![],
+        card: None,
+        ports: vec![],
+        active_port: None,
+        source_type: None,
+        removable: false,
+        members: vec![],
+        listening: false,
+        echo_cancelled: false,
+        detail: None,
+    })
+}
+
+fn control_id(card: i32, name: &str) -> String {
+    format!("hw:{}:{}", card, name)
+}
+
+fn raw_to_percent(raw: i64, (min, max): (i64, i64)) -> f32 {
+    if max <= min {
+        return 0.;
+    }
+
+    (raw - min) as f32 / (max - min) as f32 * 100.
+}
+
+fn percent_to_raw(volume: f32, (min, max): (i64, i64)) -> i64 {
+    min + ((max - min) as f32 * volume.clamp(0., 100.) / 100.).round() as i64
+}
+
+fn alsa_error(e: alsa::Error) -> Exception {
+    Exception::Misc(format!("ALSA error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_conversion() {
+        assert_eq!(raw_to_percent(0, (0, 87)), 0.);
+        assert_eq!(raw_to_percent(87, (0, 87)), 100.);
+        assert_eq!(raw_to_percent(5, (5, 5)), 0.);
+        assert_eq!(percent_to_raw(50., (0, 64)), 32);
+        assert_eq!(percent_to_raw(150., (-10, 10)), 10);
+        assert_eq!(percent_to_raw(0., (-10, 10)), -10);
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(control_id(1, "Master"), "hw:1:Master");
+    }
+}
@@ -0,0 +1,751 @@
+//! JACK has no volume controls of its own, so this backend registers a
+//! small gain client. Every playback, capture and client output port is
+//! routed through a pair of ports on it, and volume and mute are applied
+//! to the audio passing through.
+
+use std::{
+    collections::HashMap,
+    slice,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use jack::{
+    jack_sys::FLOAT_MONO_AUDIO, AsyncClient, AudioIn, AudioOut, Client, ClientOptions,
+    ClientStatus, Control, NotificationHandler, Port, PortFlags, PortId, ProcessHandler,
+    ProcessScope, Unowned,
+};
+
+use super::{
+    handle_change_event,
+    shared_output_list::{self, set_default_output, OutputId, SourceType, VolumeType},
+    Audio,
+};
+use crate::{
+    exception::Exception,
+    options::{Backend, OPTIONS},
+};
+
+const CLIENT_NAME: &str = "volapplet";
+/// How long the audio thread gets to take new stages, which it does on its
+/// next cycle.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(1);
+
+type SharedOutputs = Arc<Mutex<Vec<shared_output_list::Output>>>;
+/// The stages the audio thread works on, replaced whole when they change.
+type StageSet = Arc<Vec<ProcessedStage>>;
+
+pub struct Jack {
+    sender: mpsc::Sender<Command>,
+    outputs: SharedOutputs,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum Command {
+    Rescan,
+    SetVolume(String, f32),
+    SetMuted(String, bool),
+    Quit,
+}
+
+/// A pair of ports on the gain client that one port is routed through.
+struct GainStage {
+    input: Port<AudioIn>,
+    output: Port<AudioOut>,
+    gain: Arc<AtomicU32>,
+}
+
+/// The audio thread's handles on a gain stage's ports.
+struct ProcessedStage {
+    input: Port<Unowned>,
+    output: Port<Unowned>,
+    gain: Arc<AtomicU32>,
+}
+
+struct Level {
+    volume: f32,
+    muted: bool,
+    gain: Arc<AtomicU32>,
+}
+
+struct Notifications {
+    sender: mpsc::Sender<Command>,
+}
+
+/// Takes new stage sets without locking, so it never waits on the worker.
+struct GainProcessor {
+    stages: StageSet,
+    next: mpsc::Receiver<StageSet>,
+    /// Sets the audio thread is done with, to be freed off it.
+    retired: mpsc::SyncSender<StageSet>,
+    /// A set that couldn't be handed back yet. No new set is taken until it
+    /// is, so none is ever freed on the audio thread.
+    retiring: Option<StageSet>,
+}
+
+struct Worker {
+    client: AsyncClient<Notifications, GainProcessor>,
+    stages: HashMap<String, GainStage>,
+    next_stages: mpsc::SyncSender<StageSet>,
+    retired_stages: mpsc::Receiver<StageSet>,
+    /// The set last sent to the audio thread.
+    published: StageSet,
+    levels: HashMap<String, Level>,
+    outputs: SharedOutputs,
+}
+
+impl Jack {
+    pub fn new() -> Result<Jack, Exception> {
+        let (client, _) =
+            Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER).map_err(jack_error)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let (next_stages, next) = mpsc::sync_channel(1);
+        let (retired, retired_stages) = mpsc::sync_channel(1);
+        let published: StageSet = Arc::new(vec![]);
+        let outputs: SharedOutputs = Arc::new(Mutex::new(vec![]));
+
+        let client = client
+            .activate_async(
+                Notifications {
+                    sender: sender.clone(),
+                },
+                GainProcessor {
+                    stages: published.clone(),
+                    next,
+                    retired,
+                    retiring: None,
+                },
+            )
+            .map_err(jack_error)?;
+
+        let mut worker = Worker {
+            client,
+            stages: HashMap::new(),
+            next_stages,
+            retired_stages,
+            published,
+            levels: HashMap::new(),
+            outputs: outputs.clone(),
+        };
+
+        // Have the outputs ready before the UI first asks for them.
+        worker.rescan();
+
+        let thread = thread::spawn(move || worker.run(receiver));
+
+        Ok(Jack {
+            sender,
+            outputs,
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, command: Command) {
+        if self.sender.send(command).is_err() {
+            Exception::Misc("Failed to send command to JACK.".to_string()).log_and_ignore();
+        }
+    }
+}
+
+impl Audio for Jack {
+    fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>) {
+        let outputs = self
+            .outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|output| match output.id.type_ {
+                VolumeType::Sink => true,
+                VolumeType::Input | VolumeType::Recording => OPTIONS.show_inputs,
+                VolumeType::Stream => OPTIONS.show_streams,
+            })
+            .cloned()
+            .collect();
+
+        after(outputs);
+    }
+
+    fn set_volume(&self, sink_id: OutputId, volume: f32) {
+        if let Some(name) = sink_id.name {
+            self.send(Command::SetVolume(
+                name,
+                volume.clamp(0., OPTIONS.max_volume),
+            ));
+        }
+    }
+
+    fn set_muted(&self, sink_id: OutputId, muted: bool) {
+        if let Some(name) = sink_id.name {
+            self.send(Command::SetMuted(name, muted));
+        }
+    }
+
+    fn cleanup(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.send(Command::Quit);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Jack {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+impl NotificationHandler for Notifications {
+    // JACK functions must not be called from here, so the worker is told
+    // to rescan instead.
+    fn client_registration(&mut self, _: &Client, _: &str, _: bool) {
+        let _ = self.sender.send(Command::Rescan);
+    }
+
+    fn port_registration(&mut self, _: &Client, _: PortId, _: bool) {
+        let _ = self.sender.send(Command::Rescan);
+    }
+
+    fn ports_connected(&mut self, _: &Client, _: PortId, _: PortId, _: bool) {
+        let _ = self.sender.send(Command::Rescan);
+    }
+
+    fn shutdown(&mut self, _: ClientStatus, reason: &str) {
+        Exception::Misc(format!("JACK server shut down ({}).", reason)).log_and_ignore();
+    }
+}
+
+impl GainProcessor {
+    /// Hands `stages` back to the worker, returning them if it can't take
+    /// them yet.
+    fn retire(&self, stages: StageSet) -> Option<StageSet> {
+        match self.retired.try_send(stages) {
+            Ok(()) => None,
+            Err(TrySendError::Full(stages) | TrySendError::Disconnected(stages)) => Some(stages),
+        }
+    }
+}
+
+impl ProcessHandler for GainProcessor {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        if let Some(old) = self.retiring.take() {
+            self.retiring = self.retire(old);
+        }
+        if self.retiring.is_none() {
+            if let Ok(stages) = self.next.try_recv() {
+                let old = std::mem::replace(&mut self.stages, stages);
+                self.retiring = self.retire(old);
+            }
+        }
+
+        let frames = ps.n_frames() as usize;
+        for stage in self.stages.iter() {
+            let gain = f32::from_bits(stage.gain.load(Ordering::Relaxed));
+            // Both are this client's audio ports, registered until the
+            // worker gets this set back.
+            let (input, output) = unsafe {
+                (
+                    slice::from_raw_parts(stage.input.buffer(ps.n_frames()) as *const f32, frames),
+                    slice::from_raw_parts_mut(
+                        stage.output.buffer(ps.n_frames()) as *mut f32,
+                        frames,
+                    ),
+                )
+            };
+
+            for (out, sample) in output.iter_mut().zip(input) {
+                *out = sample * gain;
+            }
+        }
+
+        Control::Continue
+    }
+}
+
+impl Level {
+    fn new() -> Level {
+        Level {
+            volume: 100.,
+            muted: false,
+            gain: Arc::new(AtomicU32::new(1f32.to_bits())),
+        }
+    }
+
+    fn apply(&self) {
+        self.gain.store(
+            gain_of(self.volume, self.muted).to_bits(),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Worker {
+    fn run(mut self, receiver: mpsc::Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            let mut rescan = false;
+            let mut next = Some(command);
+
+            // A single reconnection causes a burst of notifications, so
+            // everything queued is handled before rescanning once.
+            while let Some(command) = next {
+                match command {
+                    Command::Rescan => rescan = true,
+                    Command::SetVolume(id, volume) => self.set_level(&id, |level| {
+                        level.volume = volume;
+                    }),
+                    Command::SetMuted(id, muted) => self.set_level(&id, |level| {
+                        level.muted = muted;
+                    }),
+                    Command::Quit => {
+                        self.restore_connections();
+                        return;
+                    }
+                }
+                next = receiver.try_recv().ok();
+            }
+
+            if rescan {
+                self.rescan();
+            }
+        }
+    }
+
+    fn set_level(&mut self, id: &str, f: impl FnOnce(&mut Level)) {
+        let level = match self.levels.get_mut(id) {
+            Some(level) => level,
+            None => return,
+        };
+
+        f(level);
+        level.apply();
+
+        let mut outputs = self.outputs.lock().unwrap();
+        if let Some(output) = outputs
+            .iter_mut()
+            .find(|output| output.id.name.as_deref() == Some(id))
+        {
+            output.volume = level.volume;
+            output.muted = level.muted;
+        }
+        drop(outputs);
+
+        handle_change_event();
+    }
+
+    fn rescan(&mut self) {
+        let client = self.client.as_client();
+        let own_client = client.name().to_string();
+
+        let mut ports = vec![];
+        for name in client.ports(None, Some(FLOAT_MONO_AUDIO), PortFlags::empty()) {
+            let flags = match client.port_by_name(&name) {
+                Some(port) => port.flags(),
+                None => continue,
+            };
+
+            if let Some((type_, device_client)) = classify(&name, flags, &own_client) {
+                let id = device_id(&type_, device_client);
+                let label = device_name(&type_, device_client);
+                ports.push((name.clone(), type_, id, label));
+            }
+        }
+
+        self.remove_stages(|target| !ports.iter().any(|(name, ..)| name == target));
+        let client = self.client.as_client();
+
+        let mut added = false;
+        for (name, _, id, _) in ports.iter() {
+            let level = self.levels.entry(id.clone()).or_insert_with(Level::new);
+
+            if !self.stages.contains_key(name) {
+                match add_stage(client, name, level.gain.clone()) {
+                    Ok(stage) => {
+                        self.stages.insert(name.clone(), stage);
+                        added = true;
+                    }
+                    Err(e) => e.log_and_ignore(),
+                }
+            }
+        }
+
+        // New stages are processed before anything is routed through them.
+        if added && !self.publish_stages() {
+            Exception::Misc("JACK didn't take the new gain stages in time.".to_string())
+                .log_and_ignore();
+        }
+
+        let client = self.client.as_client();
+        for (name, type_, ..) in ports.iter() {
+            if let Some(stage) = self.stages.get(name) {
+                if let (Ok(stage_in), Ok(stage_out)) = (stage.input.name(), stage.output.name()) {
+                    route_through(client, name, type_, &stage_in, &stage_out);
+                }
+            }
+        }
+
+        let mut outputs: Vec<shared_output_list::Output> = vec![];
+        for (_, type_, id, label) in ports {
+            if outputs
+                .iter()
+                .any(|output| output.id.name.as_ref() == Some(&id))
+            {
+                continue;
+            }
+
+            let level = &self.levels[&id];
+            // Only physical capture ports are listed as inputs.
+            let source_type = matches!(type_, VolumeType::Input).then_some(SourceType::Hardware);
+            outputs.push(shared_output_list::Output {
+                name: label,
+                volume: level.volume,
+                muted: level.muted,
+                id: OutputId::new(Backend::Jack, type_, None, Some(id)),
+                icon_name: None,
+                sink_id: None,
+                channels: vec![],
+                card: None,
+                ports: vec![],
+                active_port: None,
+                source_type,
+                removable: false,
+                members: vec![],
+                listening: false,
+                echo_cancelled: false,
+                detail: None,
+            });
+        }
+        outputs.sort_by_key(|output| type_order(&output.id.type_));
+
+        self.levels.retain(|id, _| {
+            outputs
+                .iter()
+                .any(|output| output.id.name.as_ref() == Some(id))
+        });
+
+        let mut old_outputs = self.outputs.lock().unwrap();
+        let changed = old_outputs.len() != outputs.len()
+            || old_outputs
+                .iter()
+                .zip(outputs.iter())
+                .any(|(old, new)| old.id != new.id || old.name != new.name);
+
+        if !changed {
+            return;
+        }
+
+        // JACK has no default device, so the first playback device is used
+        // until another one is picked.
+        if !outputs.iter().any(|output| output.is_default()) {
+            if let Some(name) = outputs
+                .iter()
+                .find(|output| matches!(output.id.type_, VolumeType::Sink))
+                .and_then(|sink| sink.id.name.clone())
+            {
+                set_default_output(name);
+            }
+        }
+
+        *old_outputs = outputs;
+        drop(old_outputs);
+
+        handle_change_event();
+    }
+
+    /// Hands the stages to the audio thread, returning once it has let go
+    /// of the previous ones. Returns false if it didn't in time.
+    fn publish_stages(&mut self) -> bool {
+        // Left over from handovers that timed out. The set last sent isn't
+        // handed back until this one is taken.
+        while self.retired_stages.try_recv().is_ok() {}
+
+        let stages: StageSet = Arc::new(
+            self.stages
+                .values()
+                .map(|stage| ProcessedStage {
+                    input: stage.input.clone_unowned(),
+                    output: stage.output.clone_unowned(),
+                    gain: stage.gain.clone(),
+                })
+                .collect(),
+        );
+        // Fails while an earlier set is still waiting to be taken.
+        if self.next_stages.try_send(stages.clone()).is_err() {
+            return false;
+        }
+        let previous = std::mem::replace(&mut self.published, stages);
+
+        let deadline = Instant::now() + HANDOVER_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.retired_stages.recv_timeout(timeout) {
+                Ok(retired) if Arc::ptr_eq(&retired, &previous) => return true,
+                Ok(_) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn remove_stages(&mut self, f: impl Fn(&str) -> bool) {
+        let targets: Vec<String> = self.stages.keys().filter(|t| f(t)).cloned().collect();
+        if targets.is_empty() {
+            return;
+        }
+        let removed: Vec<(String, GainStage)> = targets
+            .into_iter()
+            .filter_map(|target| {
+                let stage = self.stages.remove(&target)?;
+                Some((target, stage))
+            })
+            .collect();
+
+        // The audio thread may use the ports until it lets go of them.
+        if !self.publish_stages() {
+            Exception::Misc("JACK didn't let go of old gain stages in time.".to_string())
+                .log_and_ignore();
+            // They are removed again on the next rescan.
+            self.stages.extend(removed);
+            return;
+        }
+
+        let client = self.client.as_client();
+        for (_, stage) in removed {
+            let _ = client.unregister_port(stage.input);
+            let _ = client.unregister_port(stage.output);
+        }
+    }
+
+    /// Connects everything routed through a gain stage directly again,
+    /// so audio keeps flowing after the applet exits.
+    fn restore_connections(&mut self) {
+        let client = self.client.as_client();
+
+        let stage_ports: Vec<(String, String)> = self
+            .stages
+            .values()
+            .filter_map(|stage| Some((stage.input.name().ok()?, stage.output.name().ok()?)))
+            .collect();
+
+        for (stage_in, stage_out) in stage_ports.iter() {
+            let sources = connections_of(client, stage_in);
+            let destinations = connections_of(client, stage_out);
+
+            for source in sources.iter() {
+                for destination in destinations.iter() {
+                    let _ = client.connect_ports_by_name(source, destination);
+                }
+                let _ = client.disconnect_ports_by_name(source, stage_in);
+            }
+            for destination in destinations.iter() {
+                let _ = client.disconnect_ports_by_name(stage_out, destination);
+            }
+        }
+
+        self.remove_stages(|_| true);
+    }
+}
+
+fn add_stage(client: &Client, target: &str, gain: Arc<AtomicU32>) -> Result<GainStage, Exception> {
+    let name = stage_port_name(target);
+
+    let input = client
+        .register_port(&format!("{}-in", name), AudioIn)
+        .map_err(jack_error)?;
+    let output = match client.register_port(&format!("{}-out", name), AudioOut) {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = client.unregister_port(input);
+            return Err(jack_error(e));
+        }
+    };
+
+    Ok(GainStage {
+        input,
+        output,
+        gain,
+    })
+}
+
+/// Moves the connections of `port` onto its gain stage. Playback and
+/// recording ports are fed by the stage, while capture and stream ports
+/// feed it.
+fn route_through(client: &Client, port: &str, type_: &VolumeType, stage_in: &str, stage_out: &str) {
+    let connections = connections_of(client, port);
+
+    match type_ {
+        VolumeType::Sink | VolumeType::Recording => {
+            for source in connections.iter().filter(|source| *source != stage_out) {
+                let _ = client.disconnect_ports_by_name(source, port);
+                let _ = client.connect_ports_by_name(source, stage_in);
+            }
+            if !connections.iter().any(|source| source == stage_out) {
+                let _ = client.connect_ports_by_name(stage_out, port);
+            }
+        }
+        VolumeType::Input | VolumeType::Stream => {
+            for destination in connections.iter().filter(|dest| *dest != stage_in) {
+                let _ = client.disconnect_ports_by_name(port, destination);
+                let _ = client.connect_ports_by_name(stage_out, destination);
+            }
+            if !connections
+                .iter()
+                .any(|destination| destination == stage_in)
+            {
+                let _ = client.connect_ports_by_name(port, stage_in);
+            }
+        }
+    }
+}
+
+fn connections_of(client: &Client, port: &str) -> Vec<String> {
+    client
+        .port_by_name(port)
+        .map(|port| port.get_connections())
+        .unwrap_or_default()
+}
+
+/// Works out what a port is shown as, and the client it belongs to.
+/// Physical input ports play back, physical output ports capture, and
+/// other clients' output and input ports are streams and recordings.
+fn classify<'a>(
+    port: &'a str,
+    flags: PortFlags,
+    own_client: &str,
+) -> Option<(VolumeType, &'a str)> {
+    let (client, _) = port.split_once(':')?;
+    if client == own_client {
+        return None;
+    }
+
+    let physical = flags.contains(PortFlags::IS_PHYSICAL);
+    if physical && flags.contains(PortFlags::IS_INPUT) {
+        Some((VolumeType::Sink, client))
+    } else if physical && flags.contains(PortFlags::IS_OUTPUT) {
+        Some((VolumeType::Input, client))
+    } else if flags.contains(PortFlags::IS_OUTPUT) {
+        Some((VolumeType::Stream, client))
+    } else if flags.contains(PortFlags::IS_INPUT) {
+        Some((VolumeType::Recording, client))
+    } else {
+        None
+    }
+}
+
+fn device_id(type_: &VolumeType, client: &str) -> String {
+    match type_ {
+        VolumeType::Sink => format!("playback:{}", client),
+        VolumeType::Input => format!("capture:{}", client),
+        VolumeType::Stream => format!("stream:{}", client),
+        VolumeType::Recording => format!("recording:{}", client),
+    }
+}
+
+fn device_name(type_: &VolumeType, client: &str) -> String {
+    match type_ {
+        VolumeType::Sink => format!("{} playback", client),
+        VolumeType::Input => format!("{} capture", client),
+        VolumeType::Stream | VolumeType::Recording => client.to_string(),
+    }
+}
+
+/// Port names can't contain the client separator.
+fn stage_port_name(target: &str) -> String {
+    target.replace(':', ".")
+}
+
+fn type_order(type_: &VolumeType) -> u8 {
+    match type_ {
+        VolumeType::Sink => 0,
+        VolumeType::Input => 1,
+        VolumeType::Stream => 2,
+        VolumeType::Recording => 3,
+    }
+}
+
+// Same cubic curve as PulseAudio, so 50% sounds about half as loud.
+fn gain_of(volume: f32, muted: bool) -> f32 {
+    if muted {
+        return 0.;
+    }
+
+    (volume / 100.).powi(3)
+}
+
+fn jack_error(e: jack::Error) -> Exception {
+    Exception::Misc(format!("JACK error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_classification() {
+        let playback = PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL;
+        let capture = PortFlags::IS_OUTPUT | PortFlags::IS_PHYSICAL;
+
+        assert!(matches!(
+            classify("system:playback_1", playback, CLIENT_NAME),
+            Some((VolumeType::Sink, "system"))
+        ));
+        assert!(matches!(
+            classify("system:capture_1", capture, CLIENT_NAME),
+            Some((VolumeType::Input, "system"))
+        ));
+        assert!(matches!(
+            classify("mpv:out_0", PortFlags::IS_OUTPUT, CLIENT_NAME),
+            Some((VolumeType::Stream, "mpv"))
+        ));
+        assert!(matches!(
+            classify("mpv:in_0", PortFlags::IS_INPUT, CLIENT_NAME),
+            Some((VolumeType::Recording, "mpv"))
+        ));
+        assert!(classify(
+            "volapplet:system.playback_1-out",
+            PortFlags::IS_OUTPUT,
+            CLIENT_NAME
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(stage_port_name("system:playback_1"), "system.playback_1");
+        assert_eq!(device_id(&VolumeType::Sink, "system"), "playback:system");
+        assert_eq!(device_name(&VolumeType::Input, "system"), "system capture");
+    }
+
+    #[test]
+    fn gain() {
+        assert_eq!(gain_of(100., false), 1.);
+        assert_eq!(gain_of(50., false), 0.125);
+        assert_eq!(gain_of(100., true), 0.);
+    }
+
+    #[test]
+    #[ignore = "needs a running JACK server, e.g. `jackd -d dummy`"]
+    fn playback_is_routed_through_gain_stage() {
+        let mut jack = Jack::new().unwrap();
+        assert!(jack
+            .outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|output| output.id.name.as_deref() == Some("playback:system")));
+
+        let (client, _) = Client::new("volapplet-test", ClientOptions::NO_START_SERVER).unwrap();
+        let connections = connections_of(&client, "system:playback_1");
+        assert!(connections
+            .iter()
+            .any(|port| port.ends_with(":system.playback_1-out")));
+
+        jack.cleanup();
+        assert!(connections_of(&client, "system:playback_1")
+            .iter()
+            .all(|port| !port.ends_with(":system.playback_1-out")));
+    }
+}
@@ -0,0 +1,292 @@
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+
+use crate::options::Backend;
+
+use super::{
+    handle_change_event,
+    shared_output_list::{self, ChannelPosition, ChannelVolume, OutputId, SourceType, VolumeType},
+    Audio, WrappedAudio,
+};
+
+/// The backend `AUDIO` is built with in tests.
+pub static MOCK: Lazy<Arc<MockAudio>> = Lazy::new(|| Arc::new(MockAudio::default()));
+
+pub fn wrapped() -> WrappedAudio {
+    WrappedAudio { aud: MOCK.clone() }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    SetVolume(String, f32),
+    SetMuted(String, bool),
+    SetDefault(String),
+    MoveStream(String, String),
+    SetChannelVolumes(String, Vec<ChannelVolume>),
+    SetCardProfile(String, String),
+    SetPort(String, String),
+    CreateVirtualSink(String),
+    CombineSinks(String, Vec<String>),
+    SetListening(String, bool),
+    SetEchoCancelled(String, bool),
+    RemoveVirtualSink(String),
+}
+
+/// An in-memory backend holding scripted outputs. Every call is recorded
+/// and applied to the scripted state, as a real server would.
+#[derive(Default)]
+pub struct MockAudio {
+    outputs: Mutex<Vec<shared_output_list::Output>>,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl MockAudio {
+    /// Replaces the scripted outputs and forgets any recorded calls.
+    pub fn script(&self, outputs: Vec<shared_output_list::Output>) {
+        *self.outputs.lock().unwrap() = outputs;
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// Changes a scripted output without going through the `Audio` trait,
+    /// like another application would.
+    pub fn update(&self, output_id: &str, f: impl FnOnce(&mut shared_output_list::Output)) {
+        let mut outputs = self.outputs.lock().unwrap();
+        if let Some(output) = outputs
+            .iter_mut()
+            .find(|output| output.id.name.as_deref() == Some(output_id))
+        {
+            f(output);
+        }
+    }
+
+    fn update_id(&self, output_id: &OutputId, f: impl FnOnce(&mut shared_output_list::Output)) {
+        let mut outputs = self.outputs.lock().unwrap();
+        if let Some(output) = outputs.iter_mut().find(|output| output.id == *output_id) {
+            f(output);
+        }
+    }
+
+    pub fn add(&self, output: shared_output_list::Output) {
+        self.outputs.lock().unwrap().push(output);
+    }
+
+    pub fn remove(&self, output_id: &str) {
+        self.outputs
+            .lock()
+            .unwrap()
+            .retain(|output| output.id.name.as_deref() != Some(output_id));
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Emits a change event, the same way a server subscription would.
+    pub fn emit_change(&self) {
+        handle_change_event();
+    }
+}
+
+impl Audio for MockAudio {
+    fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>) {
+        let outputs = self.outputs.lock().unwrap().clone();
+        after(outputs);
+    }
+
+    fn set_volume(&self, sink_id: OutputId, volume: f32) {
+        self.update_id(&sink_id, |output| {
+            output.volume = volume;
+            output.channels = shared_output_list::scale_channels(&output.channels, volume);
+        });
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetVolume(key(&sink_id), volume));
+    }
+
+    fn set_muted(&self, sink_id: OutputId, muted: bool) {
+        self.update_id(&sink_id, |output| output.muted = muted);
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetMuted(key(&sink_id), muted));
+    }
+
+    fn set_default(&self, sink_id: OutputId) {
+        match sink_id.type_ {
+            VolumeType::Sink => shared_output_list::set_default_output(key(&sink_id)),
+            VolumeType::Input => shared_output_list::set_default_input(key(&sink_id)),
+            VolumeType::Stream | VolumeType::Recording => {}
+        }
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetDefault(key(&sink_id)));
+    }
+
+    fn move_stream(&self, stream_id: OutputId, sink_id: OutputId) {
+        self.update_id(&stream_id, |output| output.sink_id = Some(sink_id.clone()));
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::MoveStream(key(&stream_id), key(&sink_id)));
+    }
+
+    fn set_channel_volumes(&self, sink_id: OutputId, channels: Vec<ChannelVolume>) {
+        self.update_id(&sink_id, |output| {
+            output.volume = shared_output_list::max_volume(&channels);
+            output.channels = channels.clone();
+        });
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetChannelVolumes(key(&sink_id), channels));
+    }
+
+    fn set_port(&self, sink_id: OutputId, port: String) {
+        self.update_id(&sink_id, |output| output.active_port = Some(port.clone()));
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetPort(key(&sink_id), port));
+    }
+
+    fn set_card_profile(&self, card_id: String, profile: String) {
+        for output in self.outputs.lock().unwrap().iter_mut() {
+            if let Some(card) = output.card.as_mut().filter(|card| card.id == card_id) {
+                card.active_profile = Some(profile.clone());
+            }
+        }
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetCardProfile(card_id, profile));
+    }
+
+    fn can_create_virtual_sinks(&self) -> bool {
+        true
+    }
+
+    fn create_virtual_sink(&self, name: String) {
+        let mut sink = output(&name, VolumeType::Sink, 100., false);
+        sink.source_type = Some(SourceType::Virtual);
+        sink.removable = true;
+        self.add(sink);
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::CreateVirtualSink(name));
+    }
+
+    fn combine_sinks(&self, name: String, sink_ids: Vec<OutputId>) {
+        let mut outputs = self.outputs.lock().unwrap();
+        let members = sink_ids
+            .iter()
+            .filter_map(|sink_id| outputs.iter().find(|output| output.id == *sink_id))
+            .map(|output| output.name.clone())
+            .collect();
+
+        let mut sink = output(&name, VolumeType::Sink, 100., false);
+        sink.source_type = Some(SourceType::Virtual);
+        sink.removable = true;
+        sink.members = members;
+        outputs.push(sink);
+        drop(outputs);
+
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::CombineSinks(name, sink_ids.iter().map(key).collect()));
+    }
+
+    fn remove_virtual_sink(&self, sink_id: OutputId) {
+        self.outputs
+            .lock()
+            .unwrap()
+            .retain(|output| output.id != sink_id || !output.removable);
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::RemoveVirtualSink(key(&sink_id)));
+    }
+
+    fn can_listen_to_inputs(&self) -> bool {
+        true
+    }
+
+    fn set_listening(&self, input_id: OutputId, listening: bool) {
+        self.update_id(&input_id, |output| output.listening = listening);
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetListening(key(&input_id), listening));
+    }
+
+    fn can_cancel_echo(&self) -> bool {
+        true
+    }
+
+    fn set_echo_cancelled(&self, input_id: OutputId, echo_cancelled: bool) {
+        self.update_id(&input_id, |output| output.echo_cancelled = echo_cancelled);
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call::SetEchoCancelled(key(&input_id), echo_cancelled));
+    }
+
+    fn created_devices(&self) -> Vec<String> {
+        self.outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|output| output.removable)
+            .map(|output| output.name.clone())
+            .collect()
+    }
+}
+
+/// How calls name outputs: scripted ones by name, others by their key.
+fn key(output_id: &OutputId) -> String {
+    output_id
+        .name
+        .clone()
+        .unwrap_or_else(|| output_id.to_string())
+}
+
+/// Channels of a stereo output.
+pub fn stereo(left: f32, right: f32) -> Vec<ChannelVolume> {
+    vec![
+        ChannelVolume {
+            position: ChannelPosition::Left,
+            volume: left,
+        },
+        ChannelVolume {
+            position: ChannelPosition::Right,
+            volume: right,
+        },
+    ]
+}
+
+/// Builds an output for scripting, identified by its name.
+pub fn output(id: &str, type_: VolumeType, volume: f32, muted: bool) -> shared_output_list::Output {
+    shared_output_list::Output {
+        name: id.to_string(),
+        volume,
+        muted,
+        // The mock stands in for a PulseAudio server.
+        id: OutputId::new(Backend::Pulse, type_, None, Some(id.to_string())),
+        icon_name: None,
+        sink_id: None,
+        channels: vec![],
+        card: None,
+        ports: vec![],
+        active_port: None,
+        source_type: None,
+        removable: false,
+        members: vec![],
+        listening: false,
+        echo_cancelled: false,
+        detail: None,
+    }
+}
@@ -1,24 +1,128 @@
-use std::sync::Arc;
+use std::{env, fs, path::PathBuf, sync::Arc};
 
+use gtk::glib::idle_add_once;
+
+use crate::exception::Exception;
+use crate::options::{Backend, OPTIONS};
 use crate::popout::Popout;
+use crate::tray_icon::TrayIcon;
+use crate::AUDIO;
 
-use self::shared_output_list::VolumeType;
+use self::shared_output_list::{ChannelVolume, OutputId, VolumeType};
 
+#[cfg(feature = "alsa")]
+mod alsa;
+#[cfg(feature = "jack")]
+mod jack;
+#[cfg(test)]
+pub mod mock;
+#[cfg(feature = "pipewire")]
 mod pipewire;
 mod pulseaudio;
 pub mod shared_output_list;
 
+pub use pulseaudio::{volume_from_db, volume_to_db};
+
 unsafe impl Send for WrappedAudio {}
 unsafe impl Sync for WrappedAudio {}
 pub struct WrappedAudio {
     pub aud: Arc<dyn Audio>,
 }
 
+/// Order in which backends are tried when the preferred one fails.
+const FALLBACK_ORDER: [Backend; 4] = [
+    Backend::Pipewire,
+    Backend::Pulse,
+    Backend::Jack,
+    Backend::Alsa,
+];
+
+#[cfg_attr(test, allow(dead_code))]
 pub fn get_audio() -> WrappedAudio {
-    // TODO: pick audio backend based on config
-    WrappedAudio {
-        aud: Arc::new(pulseaudio::Pulse::new()),
+    let candidates = backend_candidates(OPTIONS.backend.unwrap_or_else(detect_backend));
+
+    for (i, backend) in candidates.iter().enumerate() {
+        match connect(*backend) {
+            Ok(aud) => return WrappedAudio { aud },
+            Err(e) => {
+                e.log_and_ignore();
+                if let Some(next) = candidates.get(i + 1) {
+                    Exception::Misc(format!(
+                        "Could not use {}, falling back to {}.",
+                        backend.name(),
+                        next.name()
+                    ))
+                    .log_and_ignore();
+                }
+            }
+        }
+    }
+
+    Exception::Misc("Could not connect to any audio backend.".to_string()).log_and_exit();
+    unreachable!();
+}
+
+fn connect(backend: Backend) -> Result<Arc<dyn Audio>, Exception> {
+    match backend {
+        Backend::Pulse => Ok(Arc::new(pulseaudio::Pulse::new()?)),
+        #[cfg(feature = "pipewire")]
+        Backend::Pipewire => Ok(Arc::new(pipewire::Pipewire::new()?)),
+        #[cfg(not(feature = "pipewire"))]
+        Backend::Pipewire => Err(Exception::Misc(
+            "volapplet was built without PipeWire support.".to_string(),
+        )),
+        #[cfg(feature = "jack")]
+        Backend::Jack => Ok(Arc::new(jack::Jack::new()?)),
+        #[cfg(not(feature = "jack"))]
+        Backend::Jack => Err(Exception::Misc(
+            "volapplet was built without JACK support.".to_string(),
+        )),
+        #[cfg(feature = "alsa")]
+        Backend::Alsa => Ok(Arc::new(alsa::Alsa::new()?)),
+        #[cfg(not(feature = "alsa"))]
+        Backend::Alsa => Err(Exception::Misc(
+            "volapplet was built without ALSA support.".to_string(),
+        )),
+    }
+}
+
+fn backend_candidates(preferred: Backend) -> Vec<Backend> {
+    let mut candidates = vec![preferred];
+    candidates.extend(FALLBACK_ORDER.iter().filter(|b| **b != preferred));
+    candidates
+}
+
+/// Guesses which sound server is running from the sockets it creates.
+fn detect_backend() -> Backend {
+    if env::var_os("PULSE_SERVER").is_some() {
+        return Backend::Pulse;
+    }
+
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        if cfg!(feature = "pipewire") && runtime_dir.join("pipewire-0").exists() {
+            return Backend::Pipewire;
+        }
+        if runtime_dir.join("pulse/native").exists() {
+            return Backend::Pulse;
+        }
+    }
+
+    if cfg!(feature = "jack") && jack_server_running() {
+        return Backend::Jack;
     }
+
+    Backend::Pulse
+}
+
+/// jackd keeps its sockets and semaphores in /dev/shm.
+fn jack_server_running() -> bool {
+    fs::read_dir("/dev/shm")
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("jack"))
+        })
+        .unwrap_or(false)
 }
 
 pub fn reload_outputs_in_popout(outputs: Vec<shared_output_list::Output>) {
@@ -26,10 +130,413 @@ pub fn reload_outputs_in_popout(outputs: Vec<shared_output_list::Output>) {
     Popout::update_outputs();
 }
 
+/// Refetches the outputs after a backend reported a change, unless the
+/// popout asked to ignore it.
+#[cfg_attr(
+    not(any(test, feature = "pipewire", feature = "jack", feature = "alsa")),
+    allow(dead_code)
+)]
+pub fn handle_change_event() {
+    Popout::handle_callback(|_| {
+        // Run the update on the GTK thread rather than the backend's one.
+        idle_add_once(|| {
+            AUDIO
+                .lock()
+                .unwrap()
+                .aud
+                .get_outputs(Box::new(sink_change_subscription_event_handler));
+        });
+    });
+}
+
+pub fn sink_change_subscription_event_handler(outputs: Vec<shared_output_list::Output>) {
+    let mut old_outputs = shared_output_list::OUTPUT_LIST.lock().unwrap();
+    let same_rows = outputs.len() == old_outputs.len()
+        && outputs
+            .iter()
+            .zip(old_outputs.iter())
+            .all(|(new, old)| new.id == old.id && same_layout(old, new));
+
+    if !same_rows {
+        drop(old_outputs);
+        reload_outputs_in_popout(outputs);
+    } else {
+        for (old, output) in old_outputs.iter_mut().zip(outputs.iter()) {
+            apply_changes(old, output);
+        }
+        drop(old_outputs);
+
+        update_defaults();
+    }
+}
+
+/// Applies a single output's new state, for backends that report objects
+/// one at a time. Stream rows are added in place, while new devices
+/// rebuild the popout as they change the default selectors and choosers.
+pub fn output_changed(output: shared_output_list::Output) {
+    let mut outputs = shared_output_list::OUTPUT_LIST.lock().unwrap();
+    match outputs.iter_mut().find(|old| old.id == output.id) {
+        Some(old) if same_layout(old, &output) => apply_changes(old, &output),
+        Some(old) => {
+            *old = output;
+            drop(outputs);
+            Popout::update_outputs();
+            return;
+        }
+        None => {
+            let is_stream = matches!(output.id.type_, VolumeType::Stream | VolumeType::Recording);
+            shared_output_list::upsert(&mut outputs, output.clone());
+            drop(outputs);
+
+            if is_stream {
+                Popout::add_output(output);
+            } else {
+                Popout::update_outputs();
+                return;
+            }
+        }
+    }
+
+    update_defaults();
+}
+
+/// Drops a single output that the backend reported gone.
+pub fn output_removed(output_id: &OutputId) {
+    let mut outputs = shared_output_list::OUTPUT_LIST.lock().unwrap();
+    let position = match outputs.iter().position(|output| output.id == *output_id) {
+        Some(position) => position,
+        None => return,
+    };
+    let output = outputs.remove(position);
+    drop(outputs);
+
+    if matches!(output.id.type_, VolumeType::Stream | VolumeType::Recording) {
+        Popout::remove_output(output.id);
+    } else {
+        Popout::update_outputs();
+    }
+}
+
+/// Whether `output`'s row is built the same as `old`'s, so changes can be
+/// applied to it in place. Ports coming and going, or being plugged in,
+/// change the dropdowns, and the rest changes the row's icon, caption or
+/// buttons.
+fn same_layout(old: &shared_output_list::Output, output: &shared_output_list::Output) -> bool {
+    old.ports == output.ports
+        && old.removable == output.removable
+        && old.members == output.members
+        && old.source_type == output.source_type
+        && old.icon_name == output.icon_name
+}
+
+/// Copies what changed from `output` into `old`, updating the widgets
+/// showing it. Anything else needs the row rebuilt, see `same_layout`.
+fn apply_changes(old: &mut shared_output_list::Output, output: &shared_output_list::Output) {
+    if output.volume != old.volume {
+        old.volume = output.volume;
+        Popout::set_specific_volume(output.id.clone(), output.volume);
+
+        if output.is_default() {
+            TrayIcon::set_volume(output.volume);
+        }
+    }
+    if output.muted != old.muted {
+        old.muted = output.muted;
+        Popout::set_specific_muted(output.id.clone(), output.muted);
+        if output.is_default() {
+            TrayIcon::set_muted(output.muted);
+        }
+    }
+    if output.sink_id != old.sink_id {
+        old.sink_id = output.sink_id.clone();
+        Popout::set_specific_sink(output.id.clone(), output.sink_id.clone());
+    }
+    if output.active_port != old.active_port {
+        old.active_port = output.active_port.clone();
+        Popout::set_specific_port(output.id.clone(), output.active_port.clone());
+    }
+    if output.card != old.card {
+        old.card = output.card.clone();
+        Popout::set_specific_profile(
+            output.id.clone(),
+            output
+                .card
+                .as_ref()
+                .and_then(|card| card.active_profile.clone()),
+        );
+    }
+    if output.listening != old.listening {
+        old.listening = output.listening;
+        Popout::set_specific_listening(output.id.clone(), output.listening);
+    }
+    if output.name != old.name || output.detail != old.detail {
+        old.name = output.name.clone();
+        old.detail = output.detail.clone();
+        Popout::set_specific_label(
+            output.id.clone(),
+            output.name.clone(),
+            Popout::caption_for(output),
+        );
+    }
+    if output.echo_cancelled != old.echo_cancelled {
+        old.echo_cancelled = output.echo_cancelled;
+        Popout::set_specific_echo_cancelled(output.id.clone(), output.echo_cancelled);
+    }
+    if output.channels != old.channels {
+        old.channels = output.channels.clone();
+        Popout::set_specific_balance(
+            output.id.clone(),
+            shared_output_list::get_balance(&output.channels),
+        );
+    }
+}
+
+fn update_defaults() {
+    // The default device can change without any volume changing.
+    Popout::update_default_selectors();
+    if let Ok(output) = shared_output_list::get_default_output() {
+        TrayIcon::set_muted(output.muted);
+        TrayIcon::set_volume(output.volume);
+    }
+}
+
 pub trait Audio {
     fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>);
-    fn set_volume(&self, sink_id: String, volume: f32, type_: VolumeType);
-    fn set_muted(&self, sink_id: String, muted: bool, type_: VolumeType);
+    fn set_volume(&self, sink_id: OutputId, volume: f32);
+    fn set_muted(&self, sink_id: OutputId, muted: bool);
+
+    /// Makes a device the default one of its type. The applet has already
+    /// updated its own idea of the default, so backends without one on the
+    /// server side have nothing to do.
+    fn set_default(&self, _sink_id: OutputId) {}
+
+    /// Sends a playback stream to another sink.
+    fn move_stream(&self, _stream_id: OutputId, _sink_id: OutputId) {
+        Exception::Misc("This backend can't move streams.".to_string()).log_and_ignore();
+    }
+
+    /// Sets each channel's volume. `channels` holds one entry per channel,
+    /// in the order the backend reported them in `Output::channels`.
+    fn set_channel_volumes(&self, _sink_id: OutputId, _channels: Vec<ChannelVolume>) {
+        Exception::Misc("This backend can't set channel volumes.".to_string()).log_and_ignore();
+    }
+
+    fn set_port(&self, _sink_id: OutputId, _port: String) {
+        Exception::Misc("This backend can't switch ports.".to_string()).log_and_ignore();
+    }
+
+    fn set_card_profile(&self, _card_id: String, _profile: String) {
+        Exception::Misc("This backend can't switch card profiles.".to_string()).log_and_ignore();
+    }
+
+    fn can_create_virtual_sinks(&self) -> bool {
+        false
+    }
+
+    /// Creates a sink called `name` that plays nowhere, for mixing streams
+    /// to record or send on.
+    fn create_virtual_sink(&self, _name: String) {
+        Exception::Misc("This backend can't create virtual devices.".to_string()).log_and_ignore();
+    }
+
+    /// Creates a sink called `name` that plays on each of `sink_ids`.
+    fn combine_sinks(&self, _name: String, _sink_ids: Vec<OutputId>) {
+        Exception::Misc("This backend can't combine outputs.".to_string()).log_and_ignore();
+    }
+
+    /// Removes a sink made by `create_virtual_sink` or `combine_sinks`.
+    fn remove_virtual_sink(&self, _sink_id: OutputId) {
+        Exception::Misc("This backend can't remove virtual devices.".to_string()).log_and_ignore();
+    }
+
+    /// Names of the devices the applet created that are still there.
+    fn created_devices(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Removes everything the applet created, waiting until it is gone.
+    fn remove_created_devices(&self) {}
+
+    fn can_listen_to_inputs(&self) -> bool {
+        false
+    }
+
+    /// Plays an input back live on the default sink, or stops doing so.
+    fn set_listening(&self, _input_id: OutputId, _listening: bool) {
+        Exception::Misc("This backend can't play inputs back.".to_string()).log_and_ignore();
+    }
+
+    fn can_cancel_echo(&self) -> bool {
+        false
+    }
+
+    /// Cancels echo and noise on an input, making the result the default
+    /// input, or goes back to the previous default.
+    fn set_echo_cancelled(&self, _input_id: OutputId, _echo_cancelled: bool) {
+        Exception::Misc("This backend can't cancel echo.".to_string()).log_and_ignore();
+    }
+
+    /// Starts reporting peak levels through `Popout::set_specific_peak`.
+    /// Backends without meters report nothing.
+    fn start_peak_meters(&self) {}
+
+    fn stop_peak_meters(&self) {}
 
     fn cleanup(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::mock::{output, MOCK};
+    use crate::test_harness::{load, pump_events, row, run_on_gtk};
+    use shared_output_list::VolumeType;
+
+    #[test]
+    fn candidates() {
+        assert_eq!(
+            backend_candidates(Backend::Pulse),
+            vec![
+                Backend::Pulse,
+                Backend::Pipewire,
+                Backend::Jack,
+                Backend::Alsa
+            ]
+        );
+        assert_eq!(
+            backend_candidates(Backend::Jack),
+            vec![
+                Backend::Jack,
+                Backend::Pipewire,
+                Backend::Pulse,
+                Backend::Alsa
+            ]
+        );
+        assert_eq!(
+            backend_candidates(Backend::Alsa),
+            vec![
+                Backend::Alsa,
+                Backend::Pipewire,
+                Backend::Pulse,
+                Backend::Jack
+            ]
+        );
+    }
+
+    #[test]
+    fn external_changes_update_popout_and_tray() {
+        run_on_gtk(|| {
+            load(
+                vec![
+                    output("speakers", VolumeType::Sink, 40., false),
+                    output("mic", VolumeType::Input, 30., false),
+                ],
+                "speakers",
+            );
+
+            MOCK.update("mic", |output| output.volume = 80.);
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("mic").state, (80., false));
+            assert_eq!(TrayIcon::state(), Some((40., false)));
+
+            MOCK.update("speakers", |output| output.muted = true);
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("speakers").state, (40., true));
+            assert_eq!(TrayIcon::state(), Some((40., true)));
+        });
+    }
+
+    #[test]
+    fn single_streams_are_added_and_removed_in_place() {
+        run_on_gtk(|| {
+            load(
+                vec![output("speakers", VolumeType::Sink, 40., false)],
+                "speakers",
+            );
+
+            // Streams are told apart by index, even with the same name.
+            let stream = |index, volume| {
+                let mut stream = output("AudioStream", VolumeType::Stream, volume, false);
+                stream.id.index = Some(index);
+                stream.id.name = None;
+                stream
+            };
+            output_changed(stream(3, 70.));
+            output_changed(stream(4, 30.));
+            pump_events();
+
+            assert_eq!(row("Stream#3").state, (70., false));
+            assert_eq!(row("Stream#4").state, (30., false));
+
+            output_changed(stream(3, 20.));
+            pump_events();
+
+            assert_eq!(row("Stream#3").state, (20., false));
+            assert_eq!(row("Stream#4").state, (30., false));
+
+            output_removed(&stream(3, 20.).id);
+            pump_events();
+
+            assert_eq!(Popout::row("Stream#3"), None);
+            assert_eq!(row("Stream#4").state, (30., false));
+            assert_eq!(row("speakers").state, (40., false));
+            assert_eq!(shared_output_list::get_output_list().len(), 2);
+            assert!(MOCK.calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn added_and_removed_outputs_rebuild_popout() {
+        run_on_gtk(|| {
+            load(
+                vec![output("speakers", VolumeType::Sink, 40., false)],
+                "speakers",
+            );
+
+            MOCK.add(output("headphones", VolumeType::Sink, 55., false));
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("headphones").state, (55., false));
+
+            MOCK.remove("headphones");
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(Popout::row("headphones"), None);
+            assert_eq!(row("speakers").state, (40., false));
+        });
+    }
+
+    #[test]
+    fn changed_layouts_rebuild_the_row() {
+        run_on_gtk(|| {
+            let mut mix = output("mix", VolumeType::Sink, 50., false);
+            mix.members = vec!["speakers".to_string()];
+            load(
+                vec![
+                    output("speakers", VolumeType::Sink, 40., false),
+                    mix.clone(),
+                ],
+                "speakers",
+            );
+            assert!(!row("mix").removable);
+
+            mix.members.push("headphones".to_string());
+            mix.removable = true;
+            output_changed(mix);
+            pump_events();
+
+            assert_eq!(
+                row("mix").caption.as_deref(),
+                Some("Plays on speakers, headphones")
+            );
+            assert!(row("mix").removable);
+            assert_eq!(row("mix").state, (50., false));
+        });
+    }
+}
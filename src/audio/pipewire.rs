@@ -1,18 +1,701 @@
-use super::{shared_output_list::{self, VolumeType}, Audio};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::Cursor,
+    rc::Rc,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
 
-struct Pipewire {}
+use pipewire::{
+    context::Context,
+    main_loop::MainLoop,
+    metadata::{Metadata, MetadataListener},
+    node::{Node, NodeListener},
+    registry::{GlobalObject, Registry},
+    spa::{
+        param::ParamType,
+        pod::{
+            deserialize::PodDeserializer, serialize::PodSerializer, Object, Pod, Property, Value,
+            ValueArray,
+        },
+        sys::{
+            SPA_PROP_channelMap, SPA_PROP_channelVolumes, SPA_PROP_mute, SPA_AUDIO_CHANNEL_FL,
+            SPA_AUDIO_CHANNEL_FLC, SPA_AUDIO_CHANNEL_FR, SPA_AUDIO_CHANNEL_FRC,
+            SPA_AUDIO_CHANNEL_RL, SPA_AUDIO_CHANNEL_RLC, SPA_AUDIO_CHANNEL_RR,
+            SPA_AUDIO_CHANNEL_RRC, SPA_AUDIO_CHANNEL_SL, SPA_AUDIO_CHANNEL_SR,
+            SPA_AUDIO_CHANNEL_TFL, SPA_AUDIO_CHANNEL_TFR, SPA_AUDIO_CHANNEL_TRL,
+            SPA_AUDIO_CHANNEL_TRR,
+        },
+        utils::{dict::DictRef, Id, SpaTypes},
+    },
+    types::ObjectType,
+};
+
+use super::{
+    handle_change_event,
+    shared_output_list::{
+        self, set_default_input, set_default_output, ChannelPosition, ChannelVolume, OutputId,
+        SourceType, VolumeType,
+    },
+    Audio,
+};
+use crate::{
+    exception::Exception,
+    options::{Backend, OPTIONS},
+};
+
+type SharedNodes = Arc<Mutex<HashMap<u32, NodeState>>>;
+/// Output and input node of each link, by link id.
+type SharedLinks = Arc<Mutex<HashMap<u32, (u32, u32)>>>;
+type BoundNodes = Rc<RefCell<HashMap<u32, BoundNode>>>;
+
+pub struct Pipewire {
+    sender: pipewire::channel::Sender<Command>,
+    nodes: SharedNodes,
+    links: SharedLinks,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum Command {
+    SetVolume(u32, f32),
+    /// Per-channel volumes on the UI's scale.
+    SetChannelVolumes(u32, Vec<f32>),
+    SetMuted(u32, bool),
+    SetDefault(String, VolumeType),
+    MoveStream(u32, String),
+    Quit,
+}
+
+#[derive(Clone)]
+struct NodeState {
+    output: shared_output_list::Output,
+    channel_volumes: Vec<f32>,
+    channel_map: Vec<ChannelPosition>,
+}
+
+struct BoundNode {
+    proxy: Node,
+    _listener: NodeListener,
+}
+
+struct BoundMetadata {
+    proxy: Metadata,
+    _listener: MetadataListener,
+}
+
+impl Pipewire {
+    pub fn new() -> Result<Pipewire, Exception> {
+        let nodes: SharedNodes = Arc::new(Mutex::new(HashMap::new()));
+        let links: SharedLinks = Arc::new(Mutex::new(HashMap::new()));
+        let (sender, receiver) = pipewire::channel::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+
+        let thread_nodes = nodes.clone();
+        let thread_links = links.clone();
+        let thread = thread::spawn(move || {
+            if let Err(e) = run_mainloop(receiver, thread_nodes, thread_links, &ready_sender) {
+                let _ = ready_sender.send(Err(e));
+            }
+        });
+
+        match ready_receiver.recv() {
+            Ok(Ok(())) => Ok(Pipewire {
+                sender,
+                nodes,
+                links,
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Exception::Misc(
+                "PipeWire thread exited before connecting.".to_string(),
+            )),
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if self.sender.send(command).is_err() {
+            Exception::Misc("Failed to send command to PipeWire.".to_string()).log_and_ignore();
+        }
+    }
+}
 
-#[allow(unused_variables)] // Remove once implemented
 impl Audio for Pipewire {
     fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>) {
-        todo!()
+        let nodes = self.nodes.lock().unwrap();
+
+        let mut outputs: Vec<(u32, shared_output_list::Output)> = nodes
+            .iter()
+            // Nodes are only listed once their volume is known.
+            .filter(|(_, node)| !node.channel_volumes.is_empty())
+            .filter(|(_, node)| match node.output.id.type_ {
+                VolumeType::Sink => true,
+                VolumeType::Input | VolumeType::Recording => OPTIONS.show_inputs,
+                VolumeType::Stream => OPTIONS.show_streams,
+            })
+            .map(|(id, node)| {
+                let mut output = node.output.clone();
+                if let VolumeType::Stream = output.id.type_ {
+                    output.sink_id = linked_sink(&nodes, &self.links, *id);
+                }
+                (*id, output)
+            })
+            .collect();
+
+        drop(nodes);
+
+        outputs.sort_by_key(|(id, output)| (type_order(&output.id.type_), *id));
+
+        after(outputs.into_iter().map(|(_, output)| output).collect());
+    }
+
+    fn set_volume(&self, sink_id: OutputId, volume: f32) {
+        if let Some(idx) = shared_output_list::get_pa_index(&sink_id) {
+            self.send(Command::SetVolume(
+                idx,
+                volume.clamp(0., OPTIONS.max_volume),
+            ));
+        }
+    }
+
+    fn set_channel_volumes(&self, sink_id: OutputId, channels: Vec<ChannelVolume>) {
+        if let Some(idx) = shared_output_list::get_pa_index(&sink_id) {
+            let volumes = channels
+                .iter()
+                .map(|channel| channel.volume.clamp(0., OPTIONS.max_volume))
+                .collect();
+            self.send(Command::SetChannelVolumes(idx, volumes));
+        }
+    }
+
+    fn set_muted(&self, sink_id: OutputId, muted: bool) {
+        if let Some(idx) = shared_output_list::get_pa_index(&sink_id) {
+            self.send(Command::SetMuted(idx, muted));
+        }
+    }
+
+    fn set_default(&self, sink_id: OutputId) {
+        if let Some(name) = sink_id.name {
+            self.send(Command::SetDefault(name, sink_id.type_));
+        }
     }
 
-    fn set_volume(&self, sink_id: String, volume: f32, type_: VolumeType) {
-        todo!()
+    fn move_stream(&self, stream_id: OutputId, sink_id: OutputId) {
+        if let (Some(idx), Some(sink_name)) =
+            (shared_output_list::get_pa_index(&stream_id), sink_id.name)
+        {
+            self.send(Command::MoveStream(idx, sink_name));
+        }
+    }
+
+    fn cleanup(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.send(Command::Quit);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Pipewire {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+fn run_mainloop(
+    receiver: pipewire::channel::Receiver<Command>,
+    nodes: SharedNodes,
+    links: SharedLinks,
+    ready: &mpsc::Sender<Result<(), Exception>>,
+) -> Result<(), Exception> {
+    let mainloop = MainLoop::new(None).map_err(pipewire_error)?;
+    let context = Context::new(&mainloop).map_err(pipewire_error)?;
+    let core = context.connect(None).map_err(pipewire_error)?;
+    let registry = Rc::new(core.get_registry().map_err(pipewire_error)?);
+
+    let bound_nodes: BoundNodes = Rc::new(RefCell::new(HashMap::new()));
+    let bound_metadata: Rc<RefCell<Option<BoundMetadata>>> = Rc::new(RefCell::new(None));
+
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let mainloop = mainloop.clone();
+            move |id, _, _, message| {
+                if id == pipewire::core::PW_ID_CORE {
+                    Exception::Misc(format!("Disconnected from PipeWire ({}).", message))
+                        .log_and_ignore();
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    let _receiver = receiver.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        let bound_nodes = bound_nodes.clone();
+        let bound_metadata = bound_metadata.clone();
+        let nodes = nodes.clone();
+        move |command| match command {
+            Command::SetVolume(id, volume) => set_node_volume(&bound_nodes, &nodes, id, volume),
+            Command::SetChannelVolumes(id, volumes) => {
+                set_node_channel_volumes(&bound_nodes, id, volumes)
+            }
+            Command::SetMuted(id, muted) => set_node_muted(&bound_nodes, id, muted),
+            Command::SetDefault(name, type_) => set_default_node(&bound_metadata, &name, &type_),
+            Command::MoveStream(id, sink_name) => {
+                set_stream_target(&bound_metadata, id, &sink_name)
+            }
+            Command::Quit => mainloop.quit(),
+        }
+    });
+
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let registry = registry.clone();
+            let bound_nodes = bound_nodes.clone();
+            let nodes = nodes.clone();
+            let links = links.clone();
+            move |global| match global.type_ {
+                ObjectType::Node => bind_node(&registry, global, &bound_nodes, &nodes),
+                ObjectType::Metadata => bind_metadata(&registry, global, &bound_metadata),
+                ObjectType::Link => add_link(global, &links),
+                _ => {}
+            }
+        })
+        .global_remove(move |id| {
+            bound_nodes.borrow_mut().remove(&id);
+            let removed_node = nodes.lock().unwrap().remove(&id).is_some();
+            let removed_link = links.lock().unwrap().remove(&id).is_some();
+            if removed_node || removed_link {
+                handle_change_event();
+            }
+        })
+        .register();
+
+    let _ = ready.send(Ok(()));
+
+    mainloop.run();
+
+    Ok(())
+}
+
+fn bind_node(
+    registry: &Registry,
+    global: &GlobalObject<&DictRef>,
+    bound_nodes: &BoundNodes,
+    nodes: &SharedNodes,
+) {
+    let props = match global.props {
+        Some(props) => props,
+        None => return,
+    };
+
+    let (type_, source_type) = match props.get("media.class") {
+        Some("Audio/Sink") => (VolumeType::Sink, None),
+        Some("Audio/Source") => (VolumeType::Input, Some(SourceType::Hardware)),
+        Some("Audio/Source/Virtual") => (VolumeType::Input, Some(SourceType::Virtual)),
+        Some("Stream/Output/Audio") => (VolumeType::Stream, None),
+        Some("Stream/Input/Audio") => (VolumeType::Recording, None),
+        _ => return,
+    };
+
+    let proxy: Node = match registry.bind(global) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            pipewire_error(e).log_and_ignore();
+            return;
+        }
+    };
+
+    let id = global.id;
+
+    let output = shared_output_list::Output {
+        name: node_label(props, &type_),
+        volume: 0.,
+        muted: false,
+        id: node_output_id(props, id, type_),
+        icon_name: node_icon_name(props),
+        sink_id: None,
+        channels: vec![],
+        card: None,
+        ports: vec![],
+        active_port: None,
+        source_type,
+        removable: false,
+        members: vec![],
+        listening: false,
+        echo_cancelled: false,
+        detail: None,
+    };
+
+    nodes.lock().unwrap().insert(
+        id,
+        NodeState {
+            output,
+            channel_volumes: vec![],
+            channel_map: vec![],
+        },
+    );
+
+    let listener = proxy
+        .add_listener_local()
+        .param({
+            let nodes = nodes.clone();
+            move |_, param_type, _, _, pod| {
+                if param_type != ParamType::Props {
+                    return;
+                }
+
+                if let Some(pod) = pod {
+                    if update_props(&nodes, id, pod) {
+                        handle_change_event();
+                    }
+                }
+            }
+        })
+        .register();
+
+    proxy.subscribe_params(&[ParamType::Props]);
+
+    bound_nodes.borrow_mut().insert(
+        id,
+        BoundNode {
+            proxy,
+            _listener: listener,
+        },
+    );
+}
+
+fn add_link(global: &GlobalObject<&DictRef>, links: &SharedLinks) {
+    let node = |key: &str| global.props?.get(key)?.parse::<u32>().ok();
+
+    if let (Some(output), Some(input)) = (node("link.output.node"), node("link.input.node")) {
+        links.lock().unwrap().insert(global.id, (output, input));
+        handle_change_event();
+    }
+}
+
+/// Finds the sink a stream node is linked to.
+fn linked_sink(
+    nodes: &HashMap<u32, NodeState>,
+    links: &SharedLinks,
+    stream: u32,
+) -> Option<OutputId> {
+    links
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|(output, _)| *output == stream)
+        .filter_map(|(_, input)| nodes.get(input))
+        .find(|node| matches!(node.output.id.type_, VolumeType::Sink))
+        .map(|node| node.output.id.clone())
+}
+
+fn bind_metadata(
+    registry: &Registry,
+    global: &GlobalObject<&DictRef>,
+    bound_metadata: &Rc<RefCell<Option<BoundMetadata>>>,
+) {
+    if global.props.and_then(|props| props.get("metadata.name")) != Some("default") {
+        return;
+    }
+
+    let proxy: Metadata = match registry.bind(global) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            pipewire_error(e).log_and_ignore();
+            return;
+        }
+    };
+
+    let listener = proxy
+        .add_listener_local()
+        .property(|_, key, _, value| {
+            let set_default = match key {
+                Some("default.audio.sink") => set_default_output,
+                Some("default.audio.source") => set_default_input,
+                _ => return 0,
+            };
+
+            if let Some(name) = value.and_then(parse_metadata_name) {
+                set_default(name);
+                handle_change_event();
+            }
+            0
+        })
+        .register();
+
+    bound_metadata.replace(Some(BoundMetadata {
+        proxy,
+        _listener: listener,
+    }));
+}
+
+fn update_props(nodes: &SharedNodes, id: u32, pod: &Pod) -> bool {
+    let object = match PodDeserializer::deserialize_any_from(pod.as_bytes()) {
+        Ok((_, Value::Object(object))) => object,
+        _ => return false,
+    };
+
+    let mut nodes = nodes.lock().unwrap();
+    let node = match nodes.get_mut(&id) {
+        Some(node) => node,
+        None => return false,
+    };
+
+    let mut changed = false;
+
+    for property in object.properties {
+        match (property.key, property.value) {
+            (SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(volumes))) => {
+                node.output.volume = linear_to_percent(&volumes);
+                node.channel_volumes = volumes;
+                changed = true;
+            }
+            (SPA_PROP_channelMap, Value::ValueArray(ValueArray::Id(map))) => {
+                node.channel_map = map.into_iter().map(channel_position).collect();
+                changed = true;
+            }
+            (SPA_PROP_mute, Value::Bool(muted)) => {
+                node.output.muted = muted;
+                changed = true;
+            }
+            _ => {}
+        }
+    }
+
+    if changed {
+        node.output.channels = node
+            .channel_volumes
+            .iter()
+            .enumerate()
+            .map(|(i, volume)| ChannelVolume {
+                position: node
+                    .channel_map
+                    .get(i)
+                    .copied()
+                    .unwrap_or(ChannelPosition::Center),
+                volume: linear_to_percent(&[*volume]),
+            })
+            .collect();
+    }
+
+    changed
+}
+
+fn set_node_volume(bound_nodes: &BoundNodes, nodes: &SharedNodes, id: u32, volume: f32) {
+    // Scaling the channels together keeps the balance.
+    let volumes = match nodes.lock().unwrap().get(&id) {
+        Some(node) => shared_output_list::scale_channels(&node.output.channels, volume)
+            .iter()
+            .map(|channel| channel.volume)
+            .collect(),
+        None => return,
+    };
+
+    set_node_channel_volumes(bound_nodes, id, volumes);
+}
+
+fn set_node_channel_volumes(bound_nodes: &BoundNodes, id: u32, volumes: Vec<f32>) {
+    let volumes = volumes.into_iter().map(percent_to_linear).collect();
+
+    set_node_props(
+        bound_nodes,
+        id,
+        Property::new(
+            SPA_PROP_channelVolumes,
+            Value::ValueArray(ValueArray::Float(volumes)),
+        ),
+    );
+}
+
+fn set_node_muted(bound_nodes: &BoundNodes, id: u32, muted: bool) {
+    set_node_props(
+        bound_nodes,
+        id,
+        Property::new(SPA_PROP_mute, Value::Bool(muted)),
+    );
+}
+
+fn set_node_props(bound_nodes: &BoundNodes, id: u32, property: Property) {
+    let bound_nodes = bound_nodes.borrow();
+    let node = match bound_nodes.get(&id) {
+        Some(node) => node,
+        None => return,
+    };
+
+    let props = Value::Object(Object {
+        type_: SpaTypes::ObjectParamProps.as_raw(),
+        id: ParamType::Props.as_raw(),
+        properties: vec![property],
+    });
+
+    let bytes = match PodSerializer::serialize(Cursor::new(Vec::new()), &props) {
+        Ok((cursor, _)) => cursor.into_inner(),
+        Err(_) => {
+            Exception::Misc("Failed to serialise PipeWire props.".to_string()).log_and_ignore();
+            return;
+        }
+    };
+
+    if let Some(pod) = Pod::from_bytes(&bytes) {
+        node.proxy.set_param(ParamType::Props, 0, pod);
+    }
+}
+
+/// Sets the configured default, which PipeWire keeps across restarts.
+fn set_default_node(
+    bound_metadata: &Rc<RefCell<Option<BoundMetadata>>>,
+    name: &str,
+    type_: &VolumeType,
+) {
+    let key = match type_ {
+        VolumeType::Sink => "default.configured.audio.sink",
+        VolumeType::Input => "default.configured.audio.source",
+        VolumeType::Stream | VolumeType::Recording => return,
+    };
+
+    match bound_metadata.borrow().as_ref() {
+        Some(metadata) => metadata.proxy.set_property(
+            0,
+            key,
+            Some("Spa:String:JSON"),
+            Some(&format!("{{ \"name\": \"{}\" }}", name)),
+        ),
+        None => {
+            Exception::Misc("PipeWire default metadata not found.".to_string()).log_and_ignore()
+        }
+    }
+}
+
+/// Asks the session manager to relink a stream to another sink.
+fn set_stream_target(
+    bound_metadata: &Rc<RefCell<Option<BoundMetadata>>>,
+    id: u32,
+    sink_name: &str,
+) {
+    match bound_metadata.borrow().as_ref() {
+        Some(metadata) => metadata
+            .proxy
+            .set_property(id, "target.object", None, Some(sink_name)),
+        None => {
+            Exception::Misc("PipeWire default metadata not found.".to_string()).log_and_ignore()
+        }
+    }
+}
+
+fn node_label(props: &DictRef, type_: &VolumeType) -> String {
+    let keys: &[&str] = match type_ {
+        VolumeType::Stream | VolumeType::Recording => {
+            &["media.name", "application.name", "node.name"]
+        }
+        _ => &["node.description", "node.nick", "node.name"],
+    };
+
+    keys.iter()
+        .find_map(|key| props.get(key))
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn node_output_id(props: &DictRef, id: u32, type_: VolumeType) -> OutputId {
+    let name = match type_ {
+        // Default devices are looked up by node name.
+        VolumeType::Sink | VolumeType::Input => props.get("node.name").map(|name| name.to_string()),
+        VolumeType::Stream | VolumeType::Recording => None,
+    };
+    OutputId::new(Backend::Pipewire, type_, Some(id), name)
+}
+
+fn node_icon_name(props: &DictRef) -> Option<String> {
+    [
+        "media.icon-name",
+        "window.icon-name",
+        "application.icon-name",
+    ]
+    .iter()
+    .find_map(|key| props.get(key))
+    .map(|name| name.to_string())
+}
+
+/// Extracts the node name from a `default.audio.sink` style value,
+/// e.g. `{ "name": "alsa_output.pci-0000_00_1f.3.analog-stereo" }`.
+fn parse_metadata_name(value: &str) -> Option<String> {
+    let rest = &value[value.find("\"name\"")? + "\"name\"".len()..];
+    let rest = rest.trim_start().strip_prefix(':')?;
+    let rest = rest.trim_start().strip_prefix('"')?;
+
+    Some(rest[..rest.find('"')?].to_string())
+}
+
+fn type_order(type_: &VolumeType) -> u8 {
+    match type_ {
+        VolumeType::Sink => 0,
+        VolumeType::Input => 1,
+        VolumeType::Stream => 2,
+        VolumeType::Recording => 3,
+    }
+}
+
+// PipeWire volumes are linear, while the UI uses the cubic scale
+// pavucontrol and pipewire-pulse report. Like there, the loudest channel
+// is shown.
+fn linear_to_percent(volumes: &[f32]) -> f32 {
+    volumes.iter().map(|v| v.cbrt()).fold(0., f32::max) * 100.
+}
+
+fn percent_to_linear(volume: f32) -> f32 {
+    (volume / 100.).powi(3)
+}
+
+fn channel_position(Id(channel): Id) -> ChannelPosition {
+    match channel {
+        SPA_AUDIO_CHANNEL_FL
+        | SPA_AUDIO_CHANNEL_SL
+        | SPA_AUDIO_CHANNEL_RL
+        | SPA_AUDIO_CHANNEL_FLC
+        | SPA_AUDIO_CHANNEL_RLC
+        | SPA_AUDIO_CHANNEL_TFL
+        | SPA_AUDIO_CHANNEL_TRL => ChannelPosition::Left,
+        SPA_AUDIO_CHANNEL_FR
+        | SPA_AUDIO_CHANNEL_SR
+        | SPA_AUDIO_CHANNEL_RR
+        | SPA_AUDIO_CHANNEL_FRC
+        | SPA_AUDIO_CHANNEL_RRC
+        | SPA_AUDIO_CHANNEL_TFR
+        | SPA_AUDIO_CHANNEL_TRR => ChannelPosition::Right,
+        _ => ChannelPosition::Center,
+    }
+}
+
+fn pipewire_error(e: pipewire::Error) -> Exception {
+    Exception::Misc(format!("PipeWire error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_name() {
+        assert_eq!(
+            parse_metadata_name("{\"name\":\"alsa_output.analog-stereo\"}"),
+            Some("alsa_output.analog-stereo".to_string())
+        );
+        assert_eq!(
+            parse_metadata_name("{ \"name\" : \"bluez_output\" }"),
+            Some("bluez_output".to_string())
+        );
+        assert_eq!(parse_metadata_name("{}"), None);
     }
 
-    fn set_muted(&self, sink_id: String, muted: bool, type_: VolumeType) {
-        todo!()
+    #[test]
+    fn volume_conversion() {
+        assert_eq!(linear_to_percent(&[]), 0.);
+        assert!((linear_to_percent(&[1., 1.]) - 100.).abs() < 0.001);
+        assert!((linear_to_percent(&[0.125]) - 50.).abs() < 0.001);
+        assert!((linear_to_percent(&[0.125, 1.]) - 100.).abs() < 0.001);
+        assert!((percent_to_linear(50.) - 0.125).abs() < 0.001);
     }
 }
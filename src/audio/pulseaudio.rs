@@ -1,395 +1,1536 @@
 use std::{
-    collections::HashMap,
-    ffi::{c_char, c_void},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    ffi::{c_char, c_void, CStr, CString},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
-use super::{shared_output_list::VolumeType, Audio};
+use super::{
+    shared_output_list::{ChannelPosition, ChannelVolume, OutputId, Port, SourceType, VolumeType},
+    Audio,
+};
 use crate::{
     audio::{
-        get_audio, reload_outputs_in_popout,
-        shared_output_list::{self, set_default_output},
+        output_changed, output_removed, reload_outputs_in_popout,
+        shared_output_list::{self, set_default_input, set_default_output, set_server_info},
+        sink_change_subscription_event_handler, update_defaults,
     },
     exception::Exception,
-    options::OPTIONS,
+    options::{Backend, OPTIONS},
     popout::Popout,
     tray_icon::TrayIcon,
     AUDIO,
 };
 
+use gtk::glib::{idle_add_once, timeout_add_once};
 use libpulse_sys::*;
 use once_cell::sync::Lazy;
 
-static PA_CVOLUMES: Lazy<Mutex<HashMap<String, Box<pa_cvolume>>>> =
+static PA_CVOLUMES: Lazy<Mutex<HashMap<OutputId, Box<pa_cvolume>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 static GET_SINKS_CALLBACK_ID: Lazy<Mutex<u32>> = Lazy::new(|| Mutex::new(0));
-static IN_RECONNECT_LOOP: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
+/// Failed attempts since the connection dropped, `None` while connected.
+static RECONNECT_ATTEMPTS: Lazy<Mutex<Option<u32>>> = Lazy::new(|| Mutex::new(None));
+static CONNECTION_WAITER: Lazy<Mutex<Option<mpsc::Sender<bool>>>> = Lazy::new(|| Mutex::new(None));
+/// What each output's meter records, as of the last listing.
+static PEAK_TARGETS: Lazy<Mutex<HashMap<OutputId, PeakTarget>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// The monitor source of each sink, by index.
+static SINK_MONITORS: Lazy<Mutex<HashMap<u32, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Every card as last reported, by index, for devices fetched on their own.
+static CARDS: Lazy<Mutex<HashMap<u32, shared_output_list::Card>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// The module that created each sink, by index.
+static SINK_MODULES: Lazy<Mutex<HashMap<u32, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Modules the applet loaded, by index.
+static LOADED_MODULES: Lazy<Mutex<HashMap<u32, LoadedModule>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Running peak meters by output id, `None` while they are stopped.
+static PEAK_METERS: Lazy<Mutex<Option<HashMap<OutputId, PeakMeter>>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Operations waiting for the server's answer, by the key their callback
+/// gets.
+static OPERATIONS: Lazy<Mutex<HashMap<usize, PendingOperation>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_OPERATION_KEY: Lazy<Mutex<usize>> = Lazy::new(|| Mutex::new(0));
+/// The mainloops of the connections still open, by address.
+static OPEN_MAINLOOPS: Lazy<Mutex<HashSet<usize>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// How long the server gets to answer a change before it is rolled back.
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(5);
 
-#[allow(unused)] // TODO: Clean up unused
 pub struct Pulse {
     context: *mut pa_context,
     mainloop: *mut pa_threaded_mainloop,
 }
 
+// Connections are made off the UI thread when reconnecting, and handed
+// over to `AUDIO` once ready.
+unsafe impl Send for Pulse {}
+
 static NAME: &[u8] = b"volapplet\0";
 
 impl Pulse {
-    pub fn new() -> Pulse {
+    /// Connects to the PulseAudio server, waiting until the context is
+    /// ready so the caller can fall back to another backend on failure.
+    pub fn new() -> Result<Pulse, Exception> {
+        let (sender, receiver) = mpsc::channel();
+        *CONNECTION_WAITER.lock().unwrap() = Some(sender);
+
+        let server =
+            match &OPTIONS.server {
+                Some(server) => Some(CString::new(server.as_str()).map_err(|_| {
+                    Exception::Misc(format!("Invalid PulseAudio server: {}", server))
+                })?),
+                None => None,
+            };
+        let server_ptr = server.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+
         let mainloop = unsafe { pa_threaded_mainloop_new() };
         let mainloop_api = unsafe { pa_threaded_mainloop_get_api(mainloop) };
         let context = unsafe { pa_context_new(mainloop_api, NAME.as_ptr() as *const c_char) };
 
         unsafe {
-            pa_context_connect(context, std::ptr::null(), 0, std::ptr::null_mut());
-            pa_threaded_mainloop_start(mainloop);
             pa_context_set_state_callback(
                 context,
                 Some(context_state_callback),
                 std::ptr::null_mut(),
             );
-        }
 
-        Pulse { context, mainloop }
-    }
+            if pa_context_connect(context, server_ptr, 0, std::ptr::null_mut()) < 0 {
+                let reason = context_error(context);
+                destroy_connection(context, mainloop);
+                return Err(Exception::Misc(format!(
+                    "Failed to connect to PulseAudio{}: {}.",
+                    server_description(),
+                    reason
+                )));
+            }
 
-    fn get_server_info(&self) {
-        unsafe {
-            let op = pa_context_get_server_info(
-                self.context,
-                Some(server_info_callback),
-                std::ptr::null_mut(),
-            );
+            pa_threaded_mainloop_start(mainloop);
+        }
 
-            if op.is_null() {
-                Exception::Misc("Failed to get PA server info.".to_string()).log_and_ignore();
-            } else {
-                pa_operation_unref(op);
+        match receiver.recv_timeout(CONNECTION_TIMEOUT) {
+            Ok(true) => {
+                OPEN_MAINLOOPS.lock().unwrap().insert(mainloop as usize);
+                Ok(Pulse { context, mainloop })
+            }
+            _ => {
+                unsafe { destroy_connection(context, mainloop) };
+                Err(Exception::Misc(format!(
+                    "Failed to connect to PulseAudio{}.",
+                    server_description()
+                )))
             }
         }
     }
-}
-
-struct GetSinkListUserdata {
-    final_callback: Mutex<Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>>,
-    unfinished_callbacks: Mutex<u32>,
-    call_id: u32,
-    list: Mutex<Vec<shared_output_list::Output>>,
-}
 
-impl Audio for Pulse {
-    fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>) {
-        self.get_server_info();
-
-        *GET_SINKS_CALLBACK_ID.lock().unwrap() += 1;
+    /// Sends an operation, reporting it if it fails or goes unanswered and
+    /// restoring `output_id` from the server, or everything if `None`.
+    /// `start` gets the completion callback and its userdata.
+    fn track(
+        &self,
+        description: &str,
+        output_id: Option<OutputId>,
+        start: impl FnOnce(pa_context_success_cb_t, *mut c_void) -> *mut pa_operation,
+    ) {
+        let key = begin_operation(PendingOperation {
+            description: description.to_string(),
+            output_id,
+            module: None,
+            context: self.context,
+            mainloop: self.mainloop,
+        });
 
-        let mut unfinished_callbacks: u32 = 1;
+        unsafe {
+            pa_threaded_mainloop_lock(self.mainloop);
+            let op = start(Some(operation_callback), key as *mut c_void);
+            self.sent(key, op);
+            pa_threaded_mainloop_unlock(self.mainloop);
+        }
+    }
 
-        if OPTIONS.show_inputs {
-            unfinished_callbacks += 1;
+    /// The default sink's name. Without one, e.g. before the server info
+    /// has arrived, `description` is reported as failed and `output_id`
+    /// restored.
+    fn default_sink_for(&self, description: &str, output_id: &OutputId) -> Option<String> {
+        let sink = shared_output_list::DEFAULT_OUTPUT_ID
+            .lock()
+            .unwrap()
+            .clone();
+        if !sink.is_empty() {
+            return Some(sink);
         }
 
-        if OPTIONS.show_streams {
-            unfinished_callbacks += 1;
+        let operation = PendingOperation {
+            description: description.to_string(),
+            output_id: Some(output_id.clone()),
+            module: None,
+            context: self.context,
+            mainloop: self.mainloop,
+        };
+        unsafe {
+            pa_threaded_mainloop_lock(self.mainloop);
+            fail(operation, "no default output");
+            pa_threaded_mainloop_unlock(self.mainloop);
         }
+        None
+    }
 
-        let userdata = Arc::new(GetSinkListUserdata {
-            final_callback: Mutex::new(after),
-            unfinished_callbacks: Mutex::new(unfinished_callbacks),
-            call_id: *GET_SINKS_CALLBACK_ID.lock().unwrap(),
-            list: Mutex::new(vec![]),
+    /// Loads `module` with `arguments`, remembering it as `loaded` so it
+    /// can be unloaded again.
+    fn load_module(&self, description: &str, module: &str, loaded: LoadedModule) {
+        let (module, arguments) = match (CString::new(module), CString::new(&*loaded.arguments)) {
+            (Ok(module), Ok(arguments)) => (module, arguments),
+            _ => {
+                Exception::Misc(format!("Failed to {}: invalid arguments.", description))
+                    .log_and_ignore();
+                return;
+            }
+        };
+
+        let key = begin_operation(PendingOperation {
+            description: description.to_string(),
+            // A failed loopback or echo canceller shows its input as it was.
+            output_id: loaded.input().cloned(),
+            module: Some(loaded),
+            context: self.context,
+            mainloop: self.mainloop,
         });
 
         unsafe {
-            let op = pa_context_get_sink_info_list(
+            pa_threaded_mainloop_lock(self.mainloop);
+            let op = pa_context_load_module(
                 self.context,
-                Some(sink_info_callback),
-                Arc::into_raw(userdata.clone()) as *mut c_void,
+                module.as_ptr(),
+                arguments.as_ptr(),
+                Some(module_loaded_callback),
+                key as *mut c_void,
             );
+            self.sent(key, op);
+            pa_threaded_mainloop_unlock(self.mainloop);
+        }
+    }
 
-            if !op.is_null() {
-                pa_operation_unref(op);
-            }
-
-            if OPTIONS.show_inputs {
-                let op = pa_context_get_source_info_list(
-                    self.context,
-                    Some(source_info_callback),
-                    Arc::into_raw(userdata.clone()) as *mut c_void,
-                );
+    fn restore_default(&self, type_: VolumeType, name: &str) {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
 
-                if op.is_null() {
-                    Exception::Misc("Failed to get source list.".to_string()).log_and_ignore();
-                } else {
-                    pa_operation_unref(op);
-                }
-            }
+        let context = self.context;
+        let set_default = match type_ {
+            VolumeType::Sink => pa_context_set_default_sink,
+            _ => pa_context_set_default_source,
+        };
+        self.track(
+            "restore default device",
+            None,
+            |callback, userdata| unsafe { set_default(context, name.as_ptr(), callback, userdata) },
+        );
+    }
 
-            if OPTIONS.show_streams {
-                let op = pa_context_get_sink_input_info_list(
-                    self.context,
-                    Some(sink_input_info_callback),
-                    Arc::into_raw(userdata) as *mut c_void,
-                );
+    fn unload_module(&self, description: &str, module_index: u32) {
+        LOADED_MODULES.lock().unwrap().remove(&module_index);
 
-                if op.is_null() {
-                    Exception::Misc("Failed to get sink input list.".to_string()).log_and_ignore();
-                } else {
-                    pa_operation_unref(op);
-                }
-            }
-        }
+        let context = self.context;
+        self.track(description, None, |callback, userdata| unsafe {
+            pa_context_unload_module(context, module_index, callback, userdata)
+        });
     }
 
-    fn set_volume(&self, sink_id: String, volume: f32, type_: VolumeType) {
-        let volume = clamp_volume(volume);
+    /// Unloads `modules`, waiting until the server is done.
+    fn unload_modules_now(&self, modules: Vec<u32>) {
+        if self.context.is_null() {
+            return;
+        }
 
         unsafe {
             pa_threaded_mainloop_lock(self.mainloop);
-
-            let cvol = **PA_CVOLUMES.lock().unwrap().get(&sink_id).unwrap();
-            let cvol_ptr = &cvol as *const pa_cvolume as *mut pa_cvolume;
-
-            pa_cvolume_set(cvol_ptr, cvol.channels as u32, (volume * 1000.) as u32);
-
-            let idx = shared_output_list::get_pa_index(&sink_id).unwrap();
-
-            let op = match type_ {
-                VolumeType::Sink => pa_context_set_sink_volume_by_index(
-                    self.context,
-                    idx,
-                    cvol_ptr,
-                    None,
-                    std::ptr::null_mut(),
-                ),
-                VolumeType::Input => pa_context_set_source_volume_by_index(
+            for module_index in modules {
+                let op = pa_context_unload_module(
                     self.context,
-                    idx,
-                    cvol_ptr,
-                    None,
-                    std::ptr::null_mut(),
-                ),
-                VolumeType::Stream => pa_context_set_sink_input_volume(
-                    self.context,
-                    idx,
-                    cvol_ptr,
-                    None,
-                    std::ptr::null_mut(),
-                ),
-            };
-
-            if op.is_null() {
-                Exception::Misc("Failed to get PA server info.".to_string()).log_and_ignore();
-            } else {
+                    module_index,
+                    Some(signal_mainloop),
+                    self.mainloop as *mut c_void,
+                );
+                if op.is_null() {
+                    continue;
+                }
+                while pa_operation_get_state(op) == PA_OPERATION_RUNNING {
+                    pa_threaded_mainloop_wait(self.mainloop);
+                }
                 pa_operation_unref(op);
             }
-
             pa_threaded_mainloop_unlock(self.mainloop);
         }
     }
 
-    fn set_muted(&self, sink_id: String, muted: bool, type_: VolumeType) {
-        unsafe {
-            pa_threaded_mainloop_lock(self.mainloop);
+    /// Waits for the answer to the operation sent under `key`, or fails it
+    /// straight away if it couldn't be sent. Needs the mainloop locked.
+    unsafe fn sent(&self, key: usize, op: *mut pa_operation) {
+        if op.is_null() {
+            if let Some(operation) = take_operation(key) {
+                fail(operation, &context_error(self.context));
+            }
+        } else {
+            pa_operation_unref(op);
+            timeout_add_once(OPERATION_TIMEOUT, move || expire_operation(key));
+        }
+    }
 
-            let idx = shared_output_list::get_pa_index(&sink_id).unwrap();
+    fn write_cvolume(&self, sink_id: OutputId, cvol: pa_cvolume) {
+        let idx = match shared_output_list::get_pa_index(&sink_id) {
+            Some(idx) => idx,
+            None => return,
+        };
 
-            let op = match type_ {
-                VolumeType::Sink => pa_context_set_sink_mute_by_index(
-                    self.context,
-                    idx,
-                    muted as i32,
-                    None,
-                    std::ptr::null_mut(),
-                ),
-                VolumeType::Input => pa_context_set_source_mute_by_index(
-                    self.context,
-                    idx,
-                    muted as i32,
-                    None,
-                    std::ptr::null_mut(),
-                ),
-                VolumeType::Stream => pa_context_set_sink_input_mute(
-                    self.context,
-                    idx,
-                    muted as i32,
-                    None,
-                    std::ptr::null_mut(),
-                ),
-            };
+        let context = self.context;
+        let type_ = sink_id.type_.clone();
+        self.track(
+            "set volume",
+            Some(sink_id.clone()),
+            |callback, userdata| unsafe {
+                match type_ {
+                    VolumeType::Sink => {
+                        pa_context_set_sink_volume_by_index(context, idx, &cvol, callback, userdata)
+                    }
+                    VolumeType::Input => pa_context_set_source_volume_by_index(
+                        context, idx, &cvol, callback, userdata,
+                    ),
+                    VolumeType::Stream => {
+                        pa_context_set_sink_input_volume(context, idx, &cvol, callback, userdata)
+                    }
+                    VolumeType::Recording => {
+                        pa_context_set_source_output_volume(context, idx, &cvol, callback, userdata)
+                    }
+                }
+            },
+        );
 
-            if op.is_null() {
-                Exception::Misc("Failed to set muted.".to_string()).log_and_ignore();
-            } else {
-                pa_operation_unref(op);
-            }
+        PA_CVOLUMES.lock().unwrap().insert(sink_id, Box::new(cvol));
+    }
 
-            pa_threaded_mainloop_unlock(self.mainloop);
+    /// Closes this connection alone. The modules the applet loaded are
+    /// kept track of, as a server that is still running still has them.
+    fn disconnect(&mut self) {
+        if self.context.is_null() {
+            return;
         }
-    }
 
-    fn cleanup(&mut self) {
+        OPEN_MAINLOOPS
+            .lock()
+            .unwrap()
+            .remove(&(self.mainloop as usize));
+        let meters = forget_connection(self.context);
         unsafe {
-            if !self.context.is_null() {
-                pa_context_disconnect(self.context);
-                pa_context_unref(self.context);
+            pa_threaded_mainloop_lock(self.mainloop);
+            for meter in meters {
+                meter.disconnect();
             }
+            pa_threaded_mainloop_unlock(self.mainloop);
 
-            // pa_threaded_mainloop_stop(self.mainloop);
-            // pa_threaded_mainloop_free(self.mainloop);
+            destroy_connection(self.context, self.mainloop);
         }
+        self.context = std::ptr::null_mut();
+        self.mainloop = std::ptr::null_mut();
     }
 }
 
-#[no_mangle]
-extern "C" fn sink_info_callback(
-    _: *mut pa_context,
-    sink_info: *const pa_sink_info,
-    eol: i32,
-    userdata: *mut c_void,
-) {
-    let userdata = unsafe { Arc::from_raw(userdata as *mut GetSinkListUserdata) };
+/// A change sent to the server that hasn't been answered yet.
+struct PendingOperation {
+    /// What was attempted, e.g. "set volume".
+    description: String,
+    /// What to restore if it fails, everything if `None`.
+    output_id: Option<OutputId>,
+    /// What a module being loaded provides.
+    module: Option<LoadedModule>,
+    context: *mut pa_context,
+    mainloop: *mut pa_threaded_mainloop,
+}
 
-    if userdata.call_id != *GET_SINKS_CALLBACK_ID.lock().unwrap() {
-        if eol == 0 {
-            // Leak userdata again
-            let _ = Arc::into_raw(userdata);
-        }
-        return;
-    }
+unsafe impl Send for PendingOperation {}
 
-    if eol == 0 {
-        let sink_info_ptr = sink_info as *mut pa_sink_info;
+/// A module the applet loaded.
+struct LoadedModule {
+    /// What it provides, as shown in the popout.
+    name: String,
+    /// What it was loaded with, to recognise it after reconnecting.
+    arguments: String,
+    role: ModuleRole,
+}
 
-        let output_id = unsafe {
-            let name_ptr = (*sink_info_ptr).name;
-            let name = std::ffi::CStr::from_ptr(name_ptr);
-            name.to_string_lossy().to_string()
-        };
+enum ModuleRole {
+    /// A virtual sink, playing on `members` if it combines others.
+    Sink { members: Vec<String> },
+    /// Plays `source` back on the default sink.
+    Loopback { source: OutputId },
+    /// Cancels echo on `source` of what plays on `sink`. Its virtual
+    /// devices are the defaults until `previous_default` and `sink` are
+    /// restored, as the sound to cancel has to pass through `virtual_sink`.
+    /// Every canceller keeps the input that was the default before the
+    /// first one started.
+    EchoCancel {
+        source: OutputId,
+        virtual_source: String,
+        previous_default: String,
+        sink: String,
+        virtual_sink: String,
+    },
+}
 
-        let name = unsafe {
-            let desc_ptr = (*sink_info_ptr).description;
-            let desc = std::ffi::CStr::from_ptr(desc_ptr);
-            desc.to_string_lossy().to_string()
-        };
+impl LoadedModule {
+    /// The input a loopback or echo canceller is attached to.
+    fn input(&self) -> Option<&OutputId> {
+        match &self.role {
+            ModuleRole::Sink { .. } => None,
+            ModuleRole::Loopback { source } | ModuleRole::EchoCancel { source, .. } => Some(source),
+        }
+    }
+}
 
-        let muted = unsafe { (*sink_info_ptr).mute != 0 };
+fn begin_operation(operation: PendingOperation) -> usize {
+    let mut next_key = NEXT_OPERATION_KEY.lock().unwrap();
+    *next_key += 1;
+    OPERATIONS.lock().unwrap().insert(*next_key, operation);
+    *next_key
+}
 
-        let volume: f32 = unsafe {
-            let v = (*sink_info_ptr).volume;
-            PA_CVOLUMES
-                .lock()
-                .unwrap()
-                .insert(output_id.clone(), Box::new(v));
-            (pa_cvolume_avg(&v) as f32) / 1000.
-        };
+/// Removes an operation once it is answered or given up on. Only the
+/// first of the two gets it.
+fn take_operation(key: usize) -> Option<PendingOperation> {
+    OPERATIONS.lock().unwrap().remove(&key)
+}
 
-        let pa_index = unsafe { (*sink_info_ptr).index };
+#[no_mangle]
+extern "C" fn operation_callback(context: *mut pa_context, success: i32, userdata: *mut c_void) {
+    let operation = match take_operation(userdata as usize) {
+        Some(operation) => operation,
+        // Already given up on.
+        None => return,
+    };
 
-        update_list(
-            &userdata,
-            name,
-            volume,
-            muted,
-            output_id,
-            pa_index,
-            None,
-            VolumeType::Sink,
-        );
-        // Leak userdata again
-        let _ = Arc::into_raw(userdata);
-    } else {
-        // End of input
-        try_finish_callback(userdata);
+    if success == 0 {
+        unsafe { fail(operation, &context_error(context)) };
     }
 }
 
-fn try_finish_callback(userdata: Arc<GetSinkListUserdata>) {
-    let vec = userdata.list.lock().unwrap();
-    let mut unfinished_callbacks = userdata.unfinished_callbacks.lock().unwrap();
-    *unfinished_callbacks -= 1;
-    if *unfinished_callbacks == 0 {
-        userdata.final_callback.lock().unwrap()(vec.to_vec());
+#[no_mangle]
+extern "C" fn module_loaded_callback(context: *mut pa_context, index: u32, userdata: *mut c_void) {
+    let operation = match take_operation(userdata as usize) {
+        Some(operation) => operation,
+        None => return,
+    };
+
+    if index == PA_INVALID_INDEX {
+        unsafe { fail(operation, &context_error(context)) };
+    } else if let Some(module) = operation.module {
+        if let ModuleRole::EchoCancel {
+            virtual_source,
+            virtual_sink,
+            ..
+        } = &module.role
+        {
+            unsafe {
+                send_default(context, VolumeType::Input, virtual_source);
+                send_default(context, VolumeType::Sink, virtual_sink);
+            }
+        }
+        LOADED_MODULES.lock().unwrap().insert(index, module);
     }
 }
 
-#[derive(PartialEq)]
-enum SourceType {
-    Hardware,
-    Virtual,
-    Monitor,
+/// Forgets the tracked modules the server no longer has, as a restarted
+/// server reuses their indices. Ones on a server that kept running are
+/// still cleaned up later. Must be called with the mainloop locked.
+unsafe fn recheck_loaded_modules(context: *mut pa_context) {
+    let found = Box::into_raw(Box::<Vec<(u32, String)>>::default());
+    let op =
+        pa_context_get_module_info_list(context, Some(module_info_callback), found as *mut c_void);
+    if op.is_null() {
+        drop(Box::from_raw(found));
+        Exception::Misc("Failed to get module list.".to_string()).log_and_ignore();
+        LOADED_MODULES.lock().unwrap().clear();
+    } else {
+        pa_operation_unref(op);
+    }
 }
 
 #[no_mangle]
-extern "C" fn source_info_callback(
+extern "C" fn module_info_callback(
     _: *mut pa_context,
-    source_info: *const pa_source_info,
+    module_info: *const pa_module_info,
     eol: i32,
     userdata: *mut c_void,
 ) {
-    let userdata = unsafe { Arc::from_raw(userdata as *mut GetSinkListUserdata) };
-
-    if userdata.call_id != *GET_SINKS_CALLBACK_ID.lock().unwrap() {
-        if eol == 0 {
-            // Leak userdata again
-            let _ = Arc::into_raw(userdata);
-        }
+    let found = userdata as *mut Vec<(u32, String)>;
+    if eol == 0 {
+        let info = unsafe { &*module_info };
+        let arguments = if info.argument.is_null() {
+            "".to_string()
+        } else {
+            unsafe { c_string(info.argument) }
+        };
+        unsafe { (*found).push((info.index, arguments)) };
         return;
     }
 
-    if eol == 0 {
-        let source_info_ptr = source_info as *mut pa_source_info;
+    let found = unsafe { Box::from_raw(found) };
+    forget_unloaded_modules(&mut LOADED_MODULES.lock().unwrap(), &found);
+}
 
-        let source_type = if PA_INVALID_INDEX != unsafe { (*source_info_ptr).monitor_of_sink } {
-            SourceType::Monitor
-        } else {
-            if unsafe { (*source_info_ptr).flags } & PA_SOURCE_HARDWARE != 0 {
-                SourceType::Hardware
-            } else {
-                SourceType::Virtual
-            }
-        };
+/// Keeps the modules still loaded with the same arguments at their index.
+fn forget_unloaded_modules(loaded: &mut HashMap<u32, LoadedModule>, found: &[(u32, String)]) {
+    loaded.retain(|index, module| {
+        found
+            .iter()
+            .any(|(found_index, arguments)| found_index == index && *arguments == module.arguments)
+    });
+}
 
-        if source_type == SourceType::Monitor {
-            // Leak userdata again
-            let _ = Arc::into_raw(userdata);
-            return;
-        }
+/// Makes `name` the default sink or input without waiting for the answer.
+unsafe fn send_default(context: *mut pa_context, type_: VolumeType, name: &str) {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    let set_default = match type_ {
+        VolumeType::Sink => pa_context_set_default_sink,
+        _ => pa_context_set_default_source,
+    };
+    let op = set_default(context, name.as_ptr(), None, std::ptr::null_mut());
+    if !op.is_null() {
+        pa_operation_unref(op);
+    }
+}
 
-        let output_id = unsafe {
-            let name_ptr = (*source_info_ptr).name;
-            let name = std::ffi::CStr::from_ptr(name_ptr);
-            name.to_string_lossy().to_string()
-        };
+/// Puts back the defaults an echo canceller replaced, without waiting for
+/// the answer. Must be called once it is no longer tracked.
+unsafe fn send_previous_defaults(context: *mut pa_context, module: &LoadedModule) {
+    if let Some((input, sink)) = defaults_after(module, &LOADED_MODULES.lock().unwrap()) {
+        send_default(context, VolumeType::Input, &input);
+        send_default(context, VolumeType::Sink, &sink);
+    }
+}
 
-        let name = unsafe {
-            let desc_ptr = (*source_info_ptr).description;
-            let desc = std::ffi::CStr::from_ptr(desc_ptr);
-            desc.to_string_lossy().to_string()
-        };
+/// The input and sink to make the defaults once the echo canceller
+/// `removed` is gone from `loaded`: another canceller's devices while one
+/// is left, so it keeps working, or the ones from before the first.
+fn defaults_after(
+    removed: &LoadedModule,
+    loaded: &HashMap<u32, LoadedModule>,
+) -> Option<(String, String)> {
+    let (previous_default, sink) = match &removed.role {
+        ModuleRole::EchoCancel {
+            previous_default,
+            sink,
+            ..
+        } => (previous_default, sink),
+        _ => return None,
+    };
 
-        let muted = unsafe { (*source_info_ptr).mute != 0 };
+    let remaining = loaded.values().find_map(|module| match &module.role {
+        ModuleRole::EchoCancel {
+            virtual_source,
+            virtual_sink,
+            ..
+        } => Some((virtual_source.clone(), virtual_sink.clone())),
+        _ => None,
+    });
+    Some(remaining.unwrap_or_else(|| (previous_default.clone(), sink.clone())))
+}
 
-        let volume: f32 = unsafe {
-            let v = (*source_info_ptr).volume;
-            PA_CVOLUMES
-                .lock()
-                .unwrap()
-                .insert(output_id.clone(), Box::new(v));
-            (pa_cvolume_avg(&v) as f32) / 1000.
-        };
+/// The default input from before the first of the running echo
+/// cancellers started.
+fn default_before_echo_cancel(loaded: &HashMap<u32, LoadedModule>) -> Option<String> {
+    loaded.values().find_map(|module| match &module.role {
+        ModuleRole::EchoCancel {
+            previous_default, ..
+        } => Some(previous_default.clone()),
+        _ => None,
+    })
+}
 
-        let pa_index = unsafe { (*source_info_ptr).index };
+#[no_mangle]
+extern "C" fn signal_mainloop(_: *mut pa_context, _: i32, mainloop: *mut c_void) {
+    unsafe { pa_threaded_mainloop_signal(mainloop as *mut pa_threaded_mainloop, 0) };
+}
 
-        update_list(
-            &userdata,
-            name,
-            volume,
-            muted,
-            output_id,
-            pa_index,
-            None,
-            VolumeType::Input,
-        );
+/// Forgets the loaded modules that match, returning them by index.
+fn take_modules(matches: impl Fn(&LoadedModule) -> bool) -> Vec<(u32, LoadedModule)> {
+    let mut loaded = LOADED_MODULES.lock().unwrap();
+    let indices: Vec<u32> = loaded
+        .iter()
+        .filter(|(_, module)| matches(module))
+        .map(|(index, _)| *index)
+        .collect();
+    indices
+        .into_iter()
+        .filter_map(|index| Some((index, loaded.remove(&index)?)))
+        .collect()
+}
+
+/// Whether a device belongs to an echo canceller, which is shown on its
+/// input's row instead.
+fn is_hidden_module(module_index: u32) -> bool {
+    LOADED_MODULES
+        .lock()
+        .unwrap()
+        .get(&module_index)
+        .is_some_and(|module| matches!(module.role, ModuleRole::EchoCancel { .. }))
+}
+
+/// The device an echo canceller's virtual `device` stands in for, by name.
+fn echo_cancelled_device(device: &str) -> Option<String> {
+    LOADED_MODULES
+        .lock()
+        .unwrap()
+        .values()
+        .find_map(|module| match &module.role {
+            ModuleRole::EchoCancel {
+                source,
+                virtual_source,
+                sink,
+                virtual_sink,
+                ..
+            } => {
+                if virtual_source == device {
+                    source.name.clone()
+                } else if virtual_sink == device {
+                    Some(sink.clone())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+}
+
+/// Arguments for a `module-echo-cancel` on `source`, using `sink` for the
+/// sound to cancel. Both are given by their server names, and the virtual
+/// devices are named after `source`.
+fn echo_cancel_arguments(source: &str, sink: &str) -> String {
+    format!(
+        "aec_method=webrtc aec_args='noise_suppression=1' source_master={0} sink_master={1} \
+         source_name={0}.echo_cancel sink_name={0}.echo_cancel_sink",
+        source, sink
+    )
+}
+
+/// Arguments for a `module-loopback` playing `source` on `sink`, given by
+/// their server names.
+fn loopback_arguments(source: &str, sink: &str, latency_msec: u32) -> String {
+    format!(
+        "source={} sink={} latency_msec={} source_dont_move=true",
+        source, sink, latency_msec
+    )
+}
+
+/// Arguments naming the sink a module creates, shown as `name`.
+fn sink_arguments(name: &str) -> String {
+    let sink_name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect();
+    // The description is quoted twice, once for the module's arguments
+    // and once for the sink's properties.
+    let description: String = name
+        .chars()
+        .filter(|c| !matches!(c, '\'' | '"' | '\\'))
+        .collect();
+
+    format!(
+        "sink_name=volapplet_{} sink_properties='device.description=\"{}\"'",
+        sink_name, description
+    )
+}
+
+/// Arguments for a `module-combine-sink` playing on `slaves`, given by
+/// their server names.
+fn combine_sink_arguments(name: &str, slaves: &[String]) -> String {
+    format!("{} slaves={}", sink_arguments(name), slaves.join(","))
+}
+
+/// Gives up on an operation the server hasn't answered in time.
+fn expire_operation(key: usize) {
+    let operation = match take_operation(key) {
+        Some(operation) => operation,
+        None => return,
+    };
+
+    // Its connection may have closed since, freeing the mainloop.
+    let mainloop = operation.mainloop;
+    if !OPEN_MAINLOOPS
+        .lock()
+        .unwrap()
+        .contains(&(mainloop as usize))
+    {
+        return;
+    }
+    unsafe {
+        pa_threaded_mainloop_lock(mainloop);
+        fail(operation, "timed out");
+        pa_threaded_mainloop_unlock(mainloop);
+    }
+}
+
+/// Reports a failed operation and shows the server's real state again.
+/// Must be called with the mainloop locked, or from its thread.
+unsafe fn fail(operation: PendingOperation, reason: &str) {
+    Exception::Misc(format!("Failed to {}: {}.", operation.description, reason)).log_and_ignore();
+
+    // A rejected change isn't echoed back, so there is nothing to ignore.
+    Popout::clear_ignore_next_callback();
+
+    match operation
+        .output_id
+        .and_then(|output_id| Some((output_id.type_, output_id.index?)))
+    {
+        Some((type_, index)) => fetch_object(operation.context, type_, index),
+        None => list_outputs(
+            operation.context,
+            Box::new(|outputs: Vec<shared_output_list::Output>| {
+                sink_change_subscription_event_handler(outputs);
+            }),
+        ),
+    }
+}
+
+unsafe fn context_error(context: *mut pa_context) -> String {
+    CStr::from_ptr(pa_strerror(pa_context_errno(context)))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// " at <address>" when connecting somewhere other than the default.
+fn server_description() -> String {
+    match &OPTIONS.server {
+        Some(server) => format!(" at {}", server),
+        None => "".to_string(),
+    }
+}
+
+/// The server's name and version, and the host it runs on.
+unsafe fn describe_server(server_info: &pa_server_info) -> Option<String> {
+    let text =
+        |s: *const c_char| (!s.is_null()).then(|| CStr::from_ptr(s).to_string_lossy().to_string());
+
+    let mut description = text(server_info.server_name)?;
+    if let Some(version) = text(server_info.server_version) {
+        description = format!("{} {}", description, version);
+    }
+    if let Some(host) = text(server_info.host_name) {
+        description = format!("{} on {}", description, host);
+    }
+    Some(description)
+}
+
+unsafe fn request_server_info(context: *mut pa_context) {
+    let op = pa_context_get_server_info(context, Some(server_info_callback), std::ptr::null_mut());
+
+    if op.is_null() {
+        Exception::Misc("Failed to get PA server info.".to_string()).log_and_ignore();
+    } else {
+        pa_operation_unref(op);
+    }
+}
+
+/// Lists everything the options ask for, calling `after` once all of it
+/// has arrived. Must be called with the mainloop locked, or from its
+/// thread.
+unsafe fn list_outputs(
+    context: *mut pa_context,
+    after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>,
+) {
+    request_server_info(context);
+
+    *GET_SINKS_CALLBACK_ID.lock().unwrap() += 1;
+
+    // Sinks and cards are always listed.
+    let mut unfinished_callbacks: u32 = 2;
+
+    if OPTIONS.show_inputs || OPTIONS.show_monitors {
+        unfinished_callbacks += 1;
+    }
+
+    if OPTIONS.show_inputs {
+        unfinished_callbacks += 1;
+    }
+
+    if OPTIONS.show_streams {
+        unfinished_callbacks += 1;
+    }
+
+    // Meters follow the outputs while they run.
+    let after = Box::new(move |outputs: Vec<shared_output_list::Output>| {
+        after(outputs);
+        sync_peak_meters(context);
+    });
+
+    let userdata = GetSinkListUserdata::new(after, unfinished_callbacks, false);
+
+    let raw_userdata = Arc::into_raw(userdata.clone()) as *mut c_void;
+    let op = pa_context_get_sink_info_list(context, Some(sink_info_callback), raw_userdata);
+    check_listing(op, raw_userdata, "sink list");
+
+    let raw_userdata = Arc::into_raw(userdata.clone()) as *mut c_void;
+    let op = pa_context_get_card_info_list(context, Some(card_info_callback), raw_userdata);
+    check_listing(op, raw_userdata, "card list");
+
+    // Monitors are sources too.
+    if OPTIONS.show_inputs || OPTIONS.show_monitors {
+        let raw_userdata = Arc::into_raw(userdata.clone()) as *mut c_void;
+        let op = pa_context_get_source_info_list(context, Some(source_info_callback), raw_userdata);
+        check_listing(op, raw_userdata, "source list");
+    }
+
+    if OPTIONS.show_inputs {
+        let raw_userdata = Arc::into_raw(userdata.clone()) as *mut c_void;
+        let op = pa_context_get_source_output_info_list(
+            context,
+            Some(source_output_info_callback),
+            raw_userdata,
+        );
+        check_listing(op, raw_userdata, "source output list");
+    }
+
+    if OPTIONS.show_streams {
+        let raw_userdata = Arc::into_raw(userdata) as *mut c_void;
+        let op = pa_context_get_sink_input_info_list(
+            context,
+            Some(sink_input_info_callback),
+            raw_userdata,
+        );
+        check_listing(op, raw_userdata, "sink input list");
+    }
+}
+
+/// Takes back the reference handed to a listing that couldn't be started,
+/// counting it as finished so the rest of the listing still completes.
+unsafe fn check_listing(op: *mut pa_operation, raw_userdata: *mut c_void, what: &str) {
+    if op.is_null() {
+        Exception::Misc(format!("Failed to get {}.", what)).log_and_ignore();
+        try_finish_callback(Arc::from_raw(raw_userdata as *const GetSinkListUserdata));
+    } else {
+        pa_operation_unref(op);
+    }
+}
+
+/// Takes back the reference a list callback was given. Listings that were
+/// superseded are dropped, releasing it after their last call.
+unsafe fn claim_userdata(userdata: *mut c_void, eol: i32) -> Option<Arc<GetSinkListUserdata>> {
+    let userdata = Arc::from_raw(userdata as *const GetSinkListUserdata);
+
+    if userdata.call_id == *GET_SINKS_CALLBACK_ID.lock().unwrap() {
+        return Some(userdata);
+    }
+
+    if eol == 0 {
+        // Called again for the next entry.
+        let _ = Arc::into_raw(userdata);
+    }
+    None
+}
+
+struct GetSinkListUserdata {
+    final_callback: Mutex<Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>>,
+    unfinished_callbacks: Mutex<u32>,
+    call_id: u32,
+    list: Mutex<Vec<shared_output_list::Output>>,
+    /// Sink input index and the index of the sink it plays on.
+    stream_sinks: Mutex<Vec<(u32, u32)>>,
+    cards: Mutex<Vec<shared_output_list::Card>>,
+    /// Device id and the index of its card.
+    device_cards: Mutex<Vec<(OutputId, u32)>>,
+    /// Monitor source id and the index of the sink it monitors.
+    monitor_sinks: Mutex<Vec<(OutputId, u32)>>,
+    /// Set when fetching single objects, which are merged into the
+    /// current list rather than replacing it.
+    incremental: bool,
+}
+
+impl GetSinkListUserdata {
+    fn new(
+        after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>,
+        unfinished_callbacks: u32,
+        incremental: bool,
+    ) -> Arc<Self> {
+        Arc::new(GetSinkListUserdata {
+            final_callback: Mutex::new(after),
+            unfinished_callbacks: Mutex::new(unfinished_callbacks),
+            call_id: *GET_SINKS_CALLBACK_ID.lock().unwrap(),
+            list: Mutex::new(vec![]),
+            stream_sinks: Mutex::new(vec![]),
+            cards: Mutex::new(vec![]),
+            device_cards: Mutex::new(vec![]),
+            monitor_sinks: Mutex::new(vec![]),
+            incremental,
+        })
+    }
+}
+
+impl Audio for Pulse {
+    fn get_outputs(&self, after: Box<dyn Fn(Vec<shared_output_list::Output>) + 'static>) {
+        // Nothing to list until reconnected.
+        if self.context.is_null()
+            || unsafe { pa_context_get_state(self.context) } != PA_CONTEXT_READY
+        {
+            return;
+        }
+        unsafe {
+            pa_threaded_mainloop_lock(self.mainloop);
+            list_outputs(self.context, after);
+            pa_threaded_mainloop_unlock(self.mainloop);
+        }
+    }
+
+    fn set_volume(&self, sink_id: OutputId, volume: f32) {
+        let volume = clamp_volume(volume);
+
+        let mut cvol = match PA_CVOLUMES.lock().unwrap().get(&sink_id) {
+            Some(cvol) => **cvol,
+            None => return,
+        };
+
+        // Scaling rather than setting keeps the balance.
+        unsafe { pa_cvolume_scale(&mut cvol, percent_to_volume(volume)) };
+
+        self.write_cvolume(sink_id, cvol);
+    }
+
+    fn set_channel_volumes(&self, sink_id: OutputId, channels: Vec<ChannelVolume>) {
+        let mut cvol = match PA_CVOLUMES.lock().unwrap().get(&sink_id) {
+            Some(cvol) => **cvol,
+            None => return,
+        };
+
+        if channels.len() != cvol.channels as usize {
+            Exception::Misc("Channel count doesn't match.".to_string()).log_and_ignore();
+            return;
+        }
+
+        for (value, channel) in cvol.values.iter_mut().zip(channels.iter()) {
+            *value = percent_to_volume(clamp_volume(channel.volume));
+        }
+
+        self.write_cvolume(sink_id, cvol);
+    }
+
+    fn set_muted(&self, sink_id: OutputId, muted: bool) {
+        let idx = match shared_output_list::get_pa_index(&sink_id) {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        let context = self.context;
+        let muted = muted as i32;
+        let type_ = sink_id.type_.clone();
+        self.track("set muted", Some(sink_id), |callback, userdata| unsafe {
+            match type_ {
+                VolumeType::Sink => {
+                    pa_context_set_sink_mute_by_index(context, idx, muted, callback, userdata)
+                }
+                VolumeType::Input => {
+                    pa_context_set_source_mute_by_index(context, idx, muted, callback, userdata)
+                }
+                VolumeType::Stream => {
+                    pa_context_set_sink_input_mute(context, idx, muted, callback, userdata)
+                }
+                VolumeType::Recording => {
+                    pa_context_set_source_output_mute(context, idx, muted, callback, userdata)
+                }
+            }
+        });
+    }
+
+    fn set_default(&self, sink_id: OutputId) {
+        let name = match sink_id.name.clone().map(CString::new) {
+            Some(Ok(name)) => name,
+            _ => return,
+        };
+
+        let context = self.context;
+        let op = match sink_id.type_ {
+            VolumeType::Sink => pa_context_set_default_sink,
+            VolumeType::Input => pa_context_set_default_source,
+            VolumeType::Stream | VolumeType::Recording => return,
+        };
+        // The default selectors follow the server's default.
+        self.track("set default device", None, |callback, userdata| unsafe {
+            op(context, name.as_ptr(), callback, userdata)
+        });
+    }
+
+    fn set_port(&self, sink_id: OutputId, port: String) {
+        let idx = match shared_output_list::get_pa_index(&sink_id) {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        let port = match CString::new(port) {
+            Ok(port) => port,
+            Err(_) => return,
+        };
+
+        let context = self.context;
+        let op = match sink_id.type_ {
+            VolumeType::Sink => pa_context_set_sink_port_by_index,
+            VolumeType::Input => pa_context_set_source_port_by_index,
+            VolumeType::Stream | VolumeType::Recording => return,
+        };
+        self.track("set port", Some(sink_id), |callback, userdata| unsafe {
+            op(context, idx, port.as_ptr(), callback, userdata)
+        });
+    }
+
+    fn set_card_profile(&self, card_id: String, profile: String) {
+        let card_index = match shared_output_list::get_card(&card_id).and_then(|card| card.pa_index)
+        {
+            Some(card_index) => card_index,
+            None => return,
+        };
+
+        let profile = match CString::new(profile) {
+            Ok(profile) => profile,
+            Err(_) => return,
+        };
+
+        // Every device on the card shows its profile.
+        let context = self.context;
+        self.track("set card profile", None, |callback, userdata| unsafe {
+            pa_context_set_card_profile_by_index(
+                context,
+                card_index,
+                profile.as_ptr(),
+                callback,
+                userdata,
+            )
+        });
+    }
+
+    fn can_create_virtual_sinks(&self) -> bool {
+        true
+    }
+
+    fn create_virtual_sink(&self, name: String) {
+        self.load_module(
+            "create virtual device",
+            "module-null-sink",
+            LoadedModule {
+                arguments: sink_arguments(&name),
+                name,
+                role: ModuleRole::Sink { members: vec![] },
+            },
+        );
+    }
+
+    fn combine_sinks(&self, name: String, sink_ids: Vec<OutputId>) {
+        let outputs = shared_output_list::get_output_list();
+        let (slaves, members): (Vec<String>, Vec<String>) = sink_ids
+            .iter()
+            .filter_map(|sink_id| outputs.iter().find(|output| output.id == *sink_id))
+            .filter_map(|sink| Some((sink.id.name.clone()?, sink.name.clone())))
+            .unzip();
+
+        self.load_module(
+            "combine outputs",
+            "module-combine-sink",
+            LoadedModule {
+                arguments: combine_sink_arguments(&name, &slaves),
+                name,
+                role: ModuleRole::Sink { members },
+            },
+        );
+    }
+
+    fn remove_virtual_sink(&self, sink_id: OutputId) {
+        let module_index = shared_output_list::get_pa_index(&sink_id)
+            .and_then(|index| SINK_MODULES.lock().unwrap().get(&index).copied())
+            .filter(|module_index| {
+                LOADED_MODULES
+                    .lock()
+                    .unwrap()
+                    .get(module_index)
+                    .is_some_and(|module| matches!(module.role, ModuleRole::Sink { .. }))
+            });
+
+        match module_index {
+            Some(module_index) => self.unload_module("remove virtual device", module_index),
+            None => Exception::Misc(
+                "Only virtual devices created by volapplet can be removed.".to_string(),
+            )
+            .log_and_ignore(),
+        }
+    }
+
+    fn can_listen_to_inputs(&self) -> bool {
+        true
+    }
+
+    fn set_listening(&self, input_id: OutputId, listening: bool) {
+        if !listening {
+            let loopbacks = take_modules(
+                |module| matches!(&module.role, ModuleRole::Loopback { source } if *source == input_id),
+            );
+            for (module_index, _) in loopbacks {
+                self.unload_module("stop monitoring input", module_index);
+            }
+            return;
+        }
+
+        let (source, name) = match shared_output_list::get_output_list()
+            .into_iter()
+            .find(|output| output.id == input_id)
+            .and_then(|input| Some((input.id.name?, input.name)))
+        {
+            Some(input) => input,
+            None => return,
+        };
+        let sink = match self.default_sink_for("monitor input", &input_id) {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        self.load_module(
+            "monitor input",
+            "module-loopback",
+            LoadedModule {
+                name: format!("Monitor of {}", name),
+                arguments: loopback_arguments(&source, &sink, OPTIONS.monitor_latency),
+                role: ModuleRole::Loopback { source: input_id },
+            },
+        );
+    }
+
+    fn can_cancel_echo(&self) -> bool {
+        true
+    }
+
+    fn set_echo_cancelled(&self, input_id: OutputId, echo_cancelled: bool) {
+        let source = match input_id.name.clone() {
+            Some(source) => source,
+            None => return,
+        };
+
+        if !echo_cancelled {
+            let cancellers = take_modules(
+                |module| matches!(&module.role, ModuleRole::EchoCancel { source, .. } if *source == input_id),
+            );
+            for (module_index, module) in cancellers {
+                let defaults = defaults_after(&module, &LOADED_MODULES.lock().unwrap());
+                if let (ModuleRole::EchoCancel { sink, .. }, Some((default_input, default_sink))) =
+                    (&module.role, defaults)
+                {
+                    // Unless other devices were chosen since.
+                    if shared_output_list::is_default_input(&input_id) {
+                        self.restore_default(VolumeType::Input, &default_input);
+                    }
+                    if *shared_output_list::DEFAULT_OUTPUT_ID.lock().unwrap() == *sink {
+                        self.restore_default(VolumeType::Sink, &default_sink);
+                    }
+                }
+                self.unload_module("stop cancelling echo", module_index);
+            }
+            return;
+        }
+
+        let name = match shared_output_list::get_output_list()
+            .into_iter()
+            .find(|output| output.id == input_id)
+        {
+            Some(input) => input.name,
+            None => return,
+        };
+        let sink = match self.default_sink_for("cancel echo", &input_id) {
+            Some(sink) => sink,
+            None => return,
+        };
+        let previous_default = default_before_echo_cancel(&LOADED_MODULES.lock().unwrap())
+            .unwrap_or_else(|| shared_output_list::DEFAULT_INPUT_ID.lock().unwrap().clone());
+
+        self.load_module(
+            "cancel echo",
+            "module-echo-cancel",
+            LoadedModule {
+                name: format!("{} with echo cancelled", name),
+                arguments: echo_cancel_arguments(&source, &sink),
+                role: ModuleRole::EchoCancel {
+                    source: input_id,
+                    virtual_source: format!("{}.echo_cancel", source),
+                    previous_default,
+                    virtual_sink: format!("{}.echo_cancel_sink", source),
+                    sink,
+                },
+            },
+        );
+    }
+
+    fn created_devices(&self) -> Vec<String> {
+        let mut devices: Vec<String> = LOADED_MODULES
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|module| matches!(module.role, ModuleRole::Sink { .. }))
+            .map(|module| module.name.clone())
+            .collect();
+        devices.sort();
+        devices
+    }
+
+    fn remove_created_devices(&self) {
+        let devices = take_modules(|module| matches!(module.role, ModuleRole::Sink { .. }));
+        self.unload_modules_now(devices.into_iter().map(|(index, _)| index).collect());
+    }
+
+    fn move_stream(&self, stream_id: OutputId, sink_id: OutputId) {
+        let (stream_index, sink_index) = match (
+            shared_output_list::get_pa_index(&stream_id),
+            shared_output_list::get_pa_index(&sink_id),
+        ) {
+            (Some(stream_index), Some(sink_index)) => (stream_index, sink_index),
+            _ => return,
+        };
+
+        let context = self.context;
+        self.track(
+            "move stream",
+            Some(stream_id),
+            |callback, userdata| unsafe {
+                pa_context_move_sink_input_by_index(
+                    context,
+                    stream_index,
+                    sink_index,
+                    callback,
+                    userdata,
+                )
+            },
+        );
+    }
+
+    fn start_peak_meters(&self) {
+        PEAK_METERS.lock().unwrap().get_or_insert_with(HashMap::new);
+
+        unsafe {
+            pa_threaded_mainloop_lock(self.mainloop);
+            sync_peak_meters(self.context);
+            pa_threaded_mainloop_unlock(self.mainloop);
+        }
+    }
+
+    fn stop_peak_meters(&self) {
+        let meters = match PEAK_METERS.lock().unwrap().take() {
+            Some(meters) => meters,
+            None => return,
+        };
+
+        unsafe {
+            pa_threaded_mainloop_lock(self.mainloop);
+            for meter in meters.into_values() {
+                meter.disconnect();
+            }
+            pa_threaded_mainloop_unlock(self.mainloop);
+        }
+    }
+
+    /// Undoes what the applet did on the server, when quitting.
+    fn cleanup(&mut self) {
+        if self.context.is_null() {
+            return;
+        }
+
+        // Inputs shouldn't stay played back or echo cancelled once their
+        // toggles are gone.
+        let attached = take_modules(|module| module.input().is_some());
+        unsafe {
+            pa_threaded_mainloop_lock(self.mainloop);
+            for (_, module) in &attached {
+                send_previous_defaults(self.context, module);
+            }
+            pa_threaded_mainloop_unlock(self.mainloop);
+        }
+        self.unload_modules_now(attached.into_iter().map(|(index, _)| index).collect());
+        self.stop_peak_meters();
+        // Their timeouts would otherwise lock a mainloop that is gone.
+        OPERATIONS.lock().unwrap().clear();
+
+        self.disconnect();
+    }
+}
+
+/// Drops the operations waiting on `context`, whose timeouts would
+/// otherwise lock a mainloop that is gone, and returns its peak meters.
+fn forget_connection(context: *mut pa_context) -> Vec<PeakMeter> {
+    OPERATIONS
+        .lock()
+        .unwrap()
+        .retain(|_, operation| operation.context != context);
+
+    match PEAK_METERS.lock().unwrap().as_mut() {
+        Some(meters) => {
+            let ids: Vec<OutputId> = meters
+                .iter()
+                .filter(|(_, meter)| meter.context == context)
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.iter().filter_map(|id| meters.remove(id)).collect()
+        }
+        None => vec![],
+    }
+}
+
+#[no_mangle]
+extern "C" fn sink_info_callback(
+    _: *mut pa_context,
+    sink_info: *const pa_sink_info,
+    eol: i32,
+    userdata: *mut c_void,
+) {
+    let userdata = match unsafe { claim_userdata(userdata, eol) } {
+        Some(userdata) => userdata,
+        None => return,
+    };
+
+    if eol == 0 {
+        let sink_info_ptr = sink_info as *mut pa_sink_info;
+
+        // Echo cancellers are shown on their input's row instead.
+        if is_hidden_module(unsafe { (*sink_info_ptr).owner_module }) {
+            // Leak userdata again
+            let _ = Arc::into_raw(userdata);
+            return;
+        }
+
+        let pa_index = unsafe { (*sink_info_ptr).index };
+
+        let output_id = unsafe {
+            let name_ptr = (*sink_info_ptr).name;
+            let name = std::ffi::CStr::from_ptr(name_ptr);
+            OutputId::new(
+                Backend::Pulse,
+                VolumeType::Sink,
+                Some(pa_index),
+                Some(name.to_string_lossy().to_string()),
+            )
+        };
+
+        let name = unsafe {
+            let desc_ptr = (*sink_info_ptr).description;
+            let desc = std::ffi::CStr::from_ptr(desc_ptr);
+            desc.to_string_lossy().to_string()
+        };
+
+        let muted = unsafe { (*sink_info_ptr).mute != 0 };
+
+        let (volume, channels) = unsafe {
+            let v = (*sink_info_ptr).volume;
+            PA_CVOLUMES
+                .lock()
+                .unwrap()
+                .insert(output_id.clone(), Box::new(v));
+            // The loudest channel, which is what pa_cvolume_scale sets.
+            (
+                volume_to_percent(pa_cvolume_max(&v)),
+                read_channels(&v, &(*sink_info_ptr).channel_map),
+            )
+        };
+
+        let ports = unsafe {
+            let info = &*sink_info_ptr;
+            read_ports(info.ports, info.n_ports, info.active_port, |port| {
+                (port.name, port.description, port.available)
+            })
+        };
+
+        let monitor_index = unsafe { (*sink_info_ptr).monitor_source };
+        SINK_MONITORS
+            .lock()
+            .unwrap()
+            .insert(pa_index, monitor_index);
+
+        let owner_module = unsafe { (*sink_info_ptr).owner_module };
+        SINK_MODULES.lock().unwrap().insert(pa_index, owner_module);
+        let members = match LOADED_MODULES.lock().unwrap().get(&owner_module) {
+            Some(LoadedModule {
+                role: ModuleRole::Sink { members },
+                ..
+            }) => Some(members.clone()),
+            _ => None,
+        };
+        let source_type = (unsafe { (*sink_info_ptr).flags } & PA_SINK_HARDWARE == 0)
+            .then_some(SourceType::Virtual);
+
+        let card_index = unsafe { (*sink_info_ptr).card };
+        if card_index != PA_INVALID_INDEX {
+            userdata
+                .device_cards
+                .lock()
+                .unwrap()
+                .push((output_id.clone(), card_index));
+        }
+
+        let (ports, active_port) = ports;
+        update_list(
+            &userdata,
+            shared_output_list::Output {
+                name,
+                volume,
+                muted,
+                id: output_id,
+                icon_name: None,
+                sink_id: None,
+                channels,
+                card: None,
+                ports,
+                active_port,
+                source_type,
+                removable: members.is_some(),
+                members: members.unwrap_or_default(),
+                listening: false,
+                echo_cancelled: false,
+                detail: None,
+            },
+        );
+        // Leak userdata again
+        let _ = Arc::into_raw(userdata);
+    } else {
+        // End of input
+        try_finish_callback(userdata);
+    }
+}
+
+fn try_finish_callback(userdata: Arc<GetSinkListUserdata>) {
+    let mut vec = userdata.list.lock().unwrap();
+    let mut unfinished_callbacks = userdata.unfinished_callbacks.lock().unwrap();
+    *unfinished_callbacks -= 1;
+    if *unfinished_callbacks == 0 {
+        // Single objects are resolved against everything already known.
+        let mut fetched: Vec<OutputId> = vec.iter().map(|output| output.id.clone()).collect();
+        let mut list = if userdata.incremental {
+            let mut list = shared_output_list::get_output_list();
+            for output in vec.drain(..) {
+                shared_output_list::upsert(&mut list, output);
+            }
+            list
+        } else {
+            vec.drain(..).collect()
+        };
+
+        resolve_stream_sinks(&mut list, &userdata.stream_sinks.lock().unwrap());
+        resolve_monitors(&mut list, &userdata.monitor_sinks.lock().unwrap());
+        let cards: Vec<_> = CARDS.lock().unwrap().values().cloned().collect();
+        resolve_cards(&mut list, &cards, &userdata.device_cards.lock().unwrap());
+        *PEAK_TARGETS.lock().unwrap() = peak_targets(&list, &SINK_MONITORS.lock().unwrap());
+
+        if userdata.incremental {
+            fetched.extend(refresh_cards(&mut list, &userdata.cards.lock().unwrap()));
+            list.retain(|output| fetched.contains(&output.id));
+        }
+        userdata.final_callback.lock().unwrap()(list);
+    }
+}
+
+/// Streams only know their sink's index, so it is looked up once every
+/// sink has been listed.
+fn resolve_stream_sinks(list: &mut [shared_output_list::Output], stream_sinks: &[(u32, u32)]) {
+    for (stream_index, sink_index) in stream_sinks {
+        let sink_id = list
+            .iter()
+            .find(|output| {
+                matches!(output.id.type_, VolumeType::Sink) && output.id.index == Some(*sink_index)
+            })
+            .map(|sink| sink.id.clone());
+
+        if let Some(stream) = list.iter_mut().find(|output| {
+            matches!(output.id.type_, VolumeType::Stream) && output.id.index == Some(*stream_index)
+        }) {
+            stream.sink_id = sink_id;
+        }
+    }
+}
+
+/// Monitors are named after the sink they monitor, which is looked up
+/// by index like a stream's.
+fn resolve_monitors(list: &mut [shared_output_list::Output], monitor_sinks: &[(OutputId, u32)]) {
+    for (monitor_id, sink_index) in monitor_sinks {
+        let sink_name = list
+            .iter()
+            .find(|output| {
+                matches!(output.id.type_, VolumeType::Sink) && output.id.index == Some(*sink_index)
+            })
+            .map(|sink| sink.name.clone());
+
+        if let (Some(sink_name), Some(monitor)) = (
+            sink_name,
+            list.iter_mut().find(|output| output.id == *monitor_id),
+        ) {
+            monitor.name = sink_name;
+        }
+    }
+}
+
+/// Devices only know their card's index, so cards are attached once
+/// everything has been listed.
+fn resolve_cards(
+    list: &mut [shared_output_list::Output],
+    cards: &[shared_output_list::Card],
+    device_cards: &[(OutputId, u32)],
+) {
+    for (device_id, card_index) in device_cards {
+        let card = cards
+            .iter()
+            .find(|card| card.pa_index == Some(*card_index))
+            .cloned();
+
+        if let Some(device) = list.iter_mut().find(|output| output.id == *device_id) {
+            device.card = card;
+        }
+    }
+}
+
+/// Gives the devices on `cards` their new state, returning their ids.
+fn refresh_cards(
+    list: &mut [shared_output_list::Output],
+    cards: &[shared_output_list::Card],
+) -> Vec<OutputId> {
+    let mut refreshed = vec![];
+    for device in list.iter_mut() {
+        let index = device.card.as_ref().and_then(|card| card.pa_index);
+        if let Some(card) = cards
+            .iter()
+            .find(|card| index.is_some() && card.pa_index == index)
+        {
+            device.card = Some(card.clone());
+            refreshed.push(device.id.clone());
+        }
+    }
+    refreshed
+}
+
+#[no_mangle]
+extern "C" fn card_info_callback(
+    _: *mut pa_context,
+    card_info: *const pa_card_info,
+    eol: i32,
+    userdata: *mut c_void,
+) {
+    let userdata = match unsafe { claim_userdata(userdata, eol) } {
+        Some(userdata) => userdata,
+        None => return,
+    };
+
+    if eol == 0 {
+        let card = unsafe { read_card(&*card_info) };
+        if let Some(index) = card.pa_index {
+            CARDS.lock().unwrap().insert(index, card.clone());
+        }
+        userdata.cards.lock().unwrap().push(card);
         // Leak userdata again
         let _ = Arc::into_raw(userdata);
     } else {
@@ -398,53 +1539,350 @@ extern "C" fn source_info_callback(
     }
 }
 
+/// Reads a card's available profiles. The active one is always included.
+unsafe fn read_card(card_info: &pa_card_info) -> shared_output_list::Card {
+    let active_profile = if card_info.active_profile2.is_null() {
+        None
+    } else {
+        Some(c_string((*card_info.active_profile2).name))
+    };
+
+    let mut profiles = vec![];
+    for i in 0..card_info.n_profiles as usize {
+        let profile = *card_info.profiles2.add(i);
+        if profile.is_null() {
+            continue;
+        }
+
+        let name = c_string((*profile).name);
+        if (*profile).available != 0 || active_profile.as_ref() == Some(&name) {
+            profiles.push((name, c_string((*profile).description)));
+        }
+    }
+
+    shared_output_list::Card {
+        id: c_string(card_info.name),
+        pa_index: Some(card_info.index),
+        profiles,
+        active_profile,
+    }
+}
+
+/// Reads a device's ports and the name of the active one. `fields` gets
+/// a port's name, description and availability, as sinks and sources have
+/// separate port types.
+unsafe fn read_ports<T>(
+    ports: *mut *mut T,
+    n_ports: u32,
+    active_port: *mut T,
+    fields: impl Fn(&T) -> (*const c_char, *const c_char, i32),
+) -> (Vec<Port>, Option<String>) {
+    let mut list = vec![];
+    for i in 0..n_ports as usize {
+        let port = *ports.add(i);
+        if port.is_null() {
+            continue;
+        }
+
+        let (name, description, available) = fields(&*port);
+        list.push(Port {
+            name: c_string(name),
+            description: c_string(description),
+            available: port_available(available),
+        });
+    }
+
+    let active_port = if active_port.is_null() {
+        None
+    } else {
+        Some(c_string(fields(&*active_port).0))
+    };
+
+    (list, active_port)
+}
+
+fn port_available(available: i32) -> Option<bool> {
+    if available == PA_PORT_AVAILABLE_YES as i32 {
+        Some(true)
+    } else if available == PA_PORT_AVAILABLE_NO as i32 {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+unsafe fn c_string(ptr: *const c_char) -> String {
+    CStr::from_ptr(ptr).to_string_lossy().to_string()
+}
+
 #[no_mangle]
-extern "C" fn sink_input_info_callback(
+extern "C" fn source_info_callback(
     _: *mut pa_context,
-    sink_info: *const pa_sink_input_info,
+    source_info: *const pa_source_info,
     eol: i32,
     userdata: *mut c_void,
 ) {
-    let userdata = unsafe { Arc::from_raw(userdata as *mut GetSinkListUserdata) };
+    let userdata = match unsafe { claim_userdata(userdata, eol) } {
+        Some(userdata) => userdata,
+        None => return,
+    };
+
+    if eol == 0 {
+        let source_info_ptr = source_info as *mut pa_source_info;
 
-    if userdata.call_id != *GET_SINKS_CALLBACK_ID.lock().unwrap() {
-        if eol == 0 {
+        // Echo cancellers are shown on their input's row instead.
+        if is_hidden_module(unsafe { (*source_info_ptr).owner_module }) {
             // Leak userdata again
             let _ = Arc::into_raw(userdata);
+            return;
         }
-        return;
-    }
 
-    if eol == 0 {
-        let sink_info_ptr = sink_info as *mut pa_sink_input_info;
+        let source_type = if PA_INVALID_INDEX != unsafe { (*source_info_ptr).monitor_of_sink } {
+            SourceType::Monitor
+        } else {
+            if unsafe { (*source_info_ptr).flags } & PA_SOURCE_HARDWARE != 0 {
+                SourceType::Hardware
+            } else {
+                SourceType::Virtual
+            }
+        };
+
+        let shown = if source_type == SourceType::Monitor {
+            OPTIONS.show_monitors
+        } else {
+            OPTIONS.show_inputs
+        };
+
+        if !shown {
+            // Leak userdata again
+            let _ = Arc::into_raw(userdata);
+            return;
+        }
+
+        let pa_index = unsafe { (*source_info_ptr).index };
 
         let output_id = unsafe {
-            let name_ptr = (*sink_info_ptr).name;
+            let name_ptr = (*source_info_ptr).name;
             let name = std::ffi::CStr::from_ptr(name_ptr);
-            name.to_string_lossy().to_string()
+            OutputId::new(
+                Backend::Pulse,
+                VolumeType::Input,
+                Some(pa_index),
+                Some(name.to_string_lossy().to_string()),
+            )
         };
 
         let name = unsafe {
-            let desc_ptr = (*sink_info_ptr).name;
+            let desc_ptr = (*source_info_ptr).description;
             let desc = std::ffi::CStr::from_ptr(desc_ptr);
             desc.to_string_lossy().to_string()
         };
 
+        let muted = unsafe { (*source_info_ptr).mute != 0 };
+
+        let (volume, channels) = unsafe {
+            let v = (*source_info_ptr).volume;
+            PA_CVOLUMES
+                .lock()
+                .unwrap()
+                .insert(output_id.clone(), Box::new(v));
+            // The loudest channel, which is what pa_cvolume_scale sets.
+            (
+                volume_to_percent(pa_cvolume_max(&v)),
+                read_channels(&v, &(*source_info_ptr).channel_map),
+            )
+        };
+
+        let ports = unsafe {
+            let info = &*source_info_ptr;
+            read_ports(info.ports, info.n_ports, info.active_port, |port| {
+                (port.name, port.description, port.available)
+            })
+        };
+
+        if source_type == SourceType::Monitor {
+            let sink_index = unsafe { (*source_info_ptr).monitor_of_sink };
+            userdata
+                .monitor_sinks
+                .lock()
+                .unwrap()
+                .push((output_id.clone(), sink_index));
+        }
+
+        let card_index = unsafe { (*source_info_ptr).card };
+        if card_index != PA_INVALID_INDEX {
+            userdata
+                .device_cards
+                .lock()
+                .unwrap()
+                .push((output_id.clone(), card_index));
+        }
+
+        let (listening, echo_cancelled) = {
+            let loaded = LOADED_MODULES.lock().unwrap();
+            let attached = |f: fn(&ModuleRole) -> bool| {
+                loaded
+                    .values()
+                    .any(|module| f(&module.role) && module.input() == Some(&output_id))
+            };
+            (
+                attached(|role| matches!(role, ModuleRole::Loopback { .. })),
+                attached(|role| matches!(role, ModuleRole::EchoCancel { .. })),
+            )
+        };
+
+        let (ports, active_port) = ports;
+        update_list(
+            &userdata,
+            shared_output_list::Output {
+                name,
+                volume,
+                muted,
+                id: output_id,
+                icon_name: None,
+                sink_id: None,
+                channels,
+                card: None,
+                ports,
+                active_port,
+                source_type: Some(source_type),
+                removable: false,
+                members: vec![],
+                listening,
+                echo_cancelled,
+                detail: None,
+            },
+        );
+        // Leak userdata again
+        let _ = Arc::into_raw(userdata);
+    } else {
+        // End of input
+        try_finish_callback(userdata);
+    }
+}
+
+#[no_mangle]
+extern "C" fn sink_input_info_callback(
+    _: *mut pa_context,
+    sink_info: *const pa_sink_input_info,
+    eol: i32,
+    userdata: *mut c_void,
+) {
+    let userdata = match unsafe { claim_userdata(userdata, eol) } {
+        Some(userdata) => userdata,
+        None => return,
+    };
+
+    if eol == 0 {
+        let sink_info_ptr = sink_info as *mut pa_sink_input_info;
+
+        let pa_index = unsafe { (*sink_info_ptr).index };
+
+        // Streams can share a name, so only the index tells them apart.
+        let output_id = OutputId::new(Backend::Pulse, VolumeType::Stream, Some(pa_index), None);
+
+        let (name, detail) = unsafe {
+            StreamProperties::read((*sink_info_ptr).proplist).label(c_string((*sink_info_ptr).name))
+        };
+
         let muted = unsafe { (*sink_info_ptr).mute != 0 };
 
-        let volume: f32 = unsafe {
+        let (volume, channels) = unsafe {
             let v = (*sink_info_ptr).volume;
             PA_CVOLUMES
                 .lock()
                 .unwrap()
                 .insert(output_id.clone(), Box::new(v));
-            (pa_cvolume_avg(&v) as f32) / 1000.
+            // The loudest channel, which is what pa_cvolume_scale sets.
+            (
+                volume_to_percent(pa_cvolume_max(&v)),
+                read_channels(&v, &(*sink_info_ptr).channel_map),
+            )
         };
 
-        let pa_index = unsafe { (*sink_info_ptr).index };
+        let icon_name = unsafe {
+            get_icon_name((*sink_info_ptr).proplist).map(|ico_name_ptr| {
+                let icon_name = std::ffi::CStr::from_ptr(ico_name_ptr);
+                icon_name.to_string_lossy().to_string()
+            })
+        };
+
+        update_list(
+            &userdata,
+            shared_output_list::Output {
+                name,
+                volume,
+                muted,
+                id: output_id,
+                icon_name,
+                sink_id: None,
+                channels,
+                card: None,
+                ports: vec![],
+                active_port: None,
+                source_type: None,
+                removable: false,
+                members: vec![],
+                listening: false,
+                echo_cancelled: false,
+                detail,
+            },
+        );
+        let sink_index = unsafe { (*sink_info_ptr).sink };
+        userdata
+            .stream_sinks
+            .lock()
+            .unwrap()
+            .push((pa_index, sink_index));
+        // Leak userdata again
+        let _ = Arc::into_raw(userdata);
+    } else {
+        // End of input
+        try_finish_callback(userdata);
+    }
+}
+
+#[no_mangle]
+extern "C" fn source_output_info_callback(
+    _: *mut pa_context,
+    source_output_info: *const pa_source_output_info,
+    eol: i32,
+    userdata: *mut c_void,
+) {
+    let userdata = match unsafe { claim_userdata(userdata, eol) } {
+        Some(userdata) => userdata,
+        None => return,
+    };
+
+    if eol == 0 {
+        let info = unsafe { &*source_output_info };
+
+        // Peak detection streams, ours or another mixer's, aren't recordings.
+        if !info.resample_method.is_null() && unsafe { c_string(info.resample_method) } == "peaks" {
+            // Leak userdata again
+            let _ = Arc::into_raw(userdata);
+            return;
+        }
+
+        let (name, detail) =
+            unsafe { StreamProperties::read(info.proplist).label(c_string(info.name)) };
+        let output_id = OutputId::new(
+            Backend::Pulse,
+            VolumeType::Recording,
+            Some(info.index),
+            None,
+        );
+
+        PA_CVOLUMES
+            .lock()
+            .unwrap()
+            .insert(output_id.clone(), Box::new(info.volume));
+
+        let volume = volume_to_percent(unsafe { pa_cvolume_max(&info.volume) });
+        let channels = unsafe { read_channels(&info.volume, &info.channel_map) };
 
         let icon_name = unsafe {
-            get_icon_name(sink_info_ptr).map(|ico_name_ptr| {
+            get_icon_name(info.proplist).map(|ico_name_ptr| {
                 let icon_name = std::ffi::CStr::from_ptr(ico_name_ptr);
                 icon_name.to_string_lossy().to_string()
             })
@@ -452,13 +1890,24 @@ extern "C" fn sink_input_info_callback(
 
         update_list(
             &userdata,
-            name,
-            volume,
-            muted,
-            output_id,
-            pa_index,
-            icon_name,
-            VolumeType::Stream,
+            shared_output_list::Output {
+                name,
+                volume,
+                muted: info.mute != 0,
+                id: output_id,
+                icon_name,
+                sink_id: None,
+                channels,
+                card: None,
+                ports: vec![],
+                active_port: None,
+                source_type: None,
+                removable: false,
+                members: vec![],
+                listening: false,
+                echo_cancelled: false,
+                detail,
+            },
         );
         // Leak userdata again
         let _ = Arc::into_raw(userdata);
@@ -468,64 +1917,324 @@ extern "C" fn sink_input_info_callback(
     }
 }
 
-unsafe fn get_icon_name(sink_info_ptr: *mut pa_sink_input_info) -> Option<*const i8> {
-    static PA_PROP_MEDIA_ICON_NAME_: &[u8] = b"media.icon_name\0";
-    static PA_PROP_WINDOW_ICON_NAME_: &[u8] = b"window.icon_name\0";
-    static PA_PROP_APPLICATION_ICON_NAME_: &[u8] = b"application.icon_name\0";
+unsafe fn get_icon_name(proplist_ptr: *mut pa_proplist) -> Option<*const i8> {
+    static PA_PROP_MEDIA_ICON_NAME_: &[u8] = b"media.icon_name\0";
+    static PA_PROP_WINDOW_ICON_NAME_: &[u8] = b"window.icon_name\0";
+    static PA_PROP_APPLICATION_ICON_NAME_: &[u8] = b"application.icon_name\0";
+
+    if let Some(value) = try_get_icon(proplist_ptr, PA_PROP_MEDIA_ICON_NAME_.as_ptr() as *const i8)
+    {
+        return Some(value);
+    }
+
+    if let Some(value) = try_get_icon(
+        proplist_ptr,
+        PA_PROP_WINDOW_ICON_NAME_.as_ptr() as *const i8,
+    ) {
+        return Some(value);
+    }
+
+    if let Some(value) = try_get_icon(
+        proplist_ptr,
+        PA_PROP_APPLICATION_ICON_NAME_.as_ptr() as *const i8,
+    ) {
+        return Some(value);
+    };
+
+    None
+}
+
+unsafe fn try_get_icon(proplist_ptr: *mut pa_proplist, key: *const c_char) -> Option<*const i8> {
+    let ico_name_ptr = pa_proplist_gets(proplist_ptr, key);
+
+    if !ico_name_ptr.is_null() {
+        return Some(ico_name_ptr);
+    }
+    None
+}
+
+/// Reads a non-empty string property. `key` must be nul terminated.
+unsafe fn try_get_string(proplist_ptr: *mut pa_proplist, key: &[u8]) -> Option<String> {
+    try_get_icon(proplist_ptr, key.as_ptr() as *const c_char)
+        .map(|value| c_string(value).trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Stream names that say nothing about what is playing.
+const GENERIC_STREAM_NAMES: &[&str] = &[
+    "playback",
+    "record",
+    "capture",
+    "audiostream",
+    "audio stream",
+    "output",
+    "input",
+];
+
+/// What a stream's properties say about who is playing what.
+#[derive(Default)]
+struct StreamProperties {
+    application: Option<String>,
+    binary: Option<String>,
+    media_name: Option<String>,
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+impl StreamProperties {
+    unsafe fn read(proplist_ptr: *mut pa_proplist) -> StreamProperties {
+        StreamProperties {
+            application: try_get_string(proplist_ptr, b"application.name\0"),
+            binary: try_get_string(proplist_ptr, b"application.process.binary\0"),
+            media_name: try_get_string(proplist_ptr, b"media.name\0"),
+            title: try_get_string(proplist_ptr, b"media.title\0"),
+            artist: try_get_string(proplist_ptr, b"media.artist\0"),
+        }
+    }
+
+    /// The name for the stream's row, from the application, and what it is
+    /// playing for the line under it.
+    fn label(self, stream_name: String) -> (String, Option<String>) {
+        let is_useful =
+            |text: &String| !GENERIC_STREAM_NAMES.contains(&text.to_lowercase().as_str());
+        let media_name = self.media_name.or(Some(stream_name)).filter(is_useful);
+
+        let name = match self.application.or(self.binary) {
+            Some(application) => application,
+            None => match media_name {
+                Some(media_name) => return (media_name, None),
+                None => return ("Unknown stream".to_string(), None),
+            },
+        };
+        let detail = match (self.artist, self.title) {
+            (Some(artist), Some(title)) => Some(format!("{} — {}", artist, title)),
+            (None, Some(title)) => Some(title),
+            _ => media_name,
+        };
+        let detail = detail.filter(|detail| *detail != name);
+
+        (name, detail)
+    }
+}
+
+/// Reads each channel's volume, along with the side `map` puts it on.
+unsafe fn read_channels(volume: &pa_cvolume, map: &pa_channel_map) -> Vec<ChannelVolume> {
+    (0..volume.channels as usize)
+        .map(|i| ChannelVolume {
+            position: channel_position(map.map[i]),
+            volume: volume_to_percent(volume.values[i]),
+        })
+        .collect()
+}
+
+fn channel_position(position: pa_channel_position_t) -> ChannelPosition {
+    use pa_channel_position_t::*;
+
+    match position {
+        FrontLeft | RearLeft | FrontLeftOfCenter | SideLeft | TopFrontLeft | TopRearLeft => {
+            ChannelPosition::Left
+        }
+        FrontRight | RearRight | FrontRightOfCenter | SideRight | TopFrontRight | TopRearRight => {
+            ChannelPosition::Right
+        }
+        _ => ChannelPosition::Center,
+    }
+}
+
+/// Where a peak meter records from: a source, optionally narrowed down
+/// to a single sink input playing to that source's sink.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PeakTarget {
+    source: u32,
+    sink_input: Option<u32>,
+}
+
+struct PeakMeter {
+    context: *mut pa_context,
+    stream: *mut pa_stream,
+    target: PeakTarget,
+    /// The output id handed to the read callback.
+    id: *mut OutputId,
+}
+
+unsafe impl Send for PeakMeter {}
+
+impl PeakMeter {
+    /// Must be called with the mainloop locked, or from its thread.
+    unsafe fn connect(context: *mut pa_context, id: OutputId, target: PeakTarget) -> Option<Self> {
+        static PEAK_STREAM_NAME: &[u8] = b"Peak detect\0";
+
+        // One float per reading, 25 times a second.
+        let spec = pa_sample_spec {
+            format: PA_SAMPLE_FLOAT32NE,
+            rate: 25,
+            channels: 1,
+        };
+
+        let stream = pa_stream_new(
+            context,
+            PEAK_STREAM_NAME.as_ptr() as *const c_char,
+            &spec,
+            std::ptr::null(),
+        );
+        if stream.is_null() {
+            return None;
+        }
+
+        let id = Box::into_raw(Box::new(id));
+        pa_stream_set_read_callback(stream, Some(peak_read_callback), id as *mut c_void);
+
+        let mut flags = PA_STREAM_DONT_MOVE | PA_STREAM_PEAK_DETECT | PA_STREAM_ADJUST_LATENCY;
+        match target.sink_input {
+            Some(sink_input) => {
+                pa_stream_set_monitor_stream(stream, sink_input);
+            }
+            // Metering alone shouldn't keep a device awake.
+            None => flags |= PA_STREAM_DONT_INHIBIT_AUTO_SUSPEND,
+        }
+
+        let attr = pa_buffer_attr {
+            maxlength: u32::MAX,
+            tlength: 0,
+            prebuf: 0,
+            minreq: 0,
+            fragsize: std::mem::size_of::<f32>() as u32,
+        };
+
+        let source = CString::new(target.source.to_string()).unwrap();
+        let meter = PeakMeter {
+            context,
+            stream,
+            target,
+            id,
+        };
+        if pa_stream_connect_record(stream, source.as_ptr(), &attr, flags) < 0 {
+            meter.disconnect();
+            return None;
+        }
+
+        Some(meter)
+    }
+
+    /// Must be called with the mainloop locked, or from its thread.
+    unsafe fn disconnect(self) {
+        pa_stream_set_read_callback(self.stream, None, std::ptr::null_mut());
+        pa_stream_disconnect(self.stream);
+        pa_stream_unref(self.stream);
+        drop(Box::from_raw(self.id));
+    }
+}
+
+/// Works out what each sink, input and stream's meter records. Sinks and
+/// streams are metered through the sink's monitor.
+fn peak_targets(
+    list: &[shared_output_list::Output],
+    sink_monitors: &HashMap<u32, u32>,
+) -> HashMap<OutputId, PeakTarget> {
+    let monitor_of = |sink_index: u32| {
+        sink_monitors
+            .get(&sink_index)
+            .copied()
+            .filter(|monitor| *monitor != PA_INVALID_INDEX)
+    };
 
-    let proplist_ptr = (*sink_info_ptr).proplist;
+    list.iter()
+        .filter_map(|output| {
+            let index = output.id.index?;
+            let target = match output.id.type_ {
+                VolumeType::Sink => PeakTarget {
+                    source: monitor_of(index)?,
+                    sink_input: None,
+                },
+                VolumeType::Input => PeakTarget {
+                    source: index,
+                    sink_input: None,
+                },
+                VolumeType::Stream => {
+                    let sink_id = output.sink_id.as_ref()?;
+                    let sink = list.iter().find(|sink| sink.id == *sink_id)?;
+                    PeakTarget {
+                        source: monitor_of(sink.id.index?)?,
+                        sink_input: Some(index),
+                    }
+                }
+                VolumeType::Recording => return None,
+            };
+            Some((output.id.clone(), target))
+        })
+        .collect()
+}
 
-    if let Some(value) = try_get_icon(proplist_ptr, PA_PROP_MEDIA_ICON_NAME_.as_ptr() as *const i8)
-    {
-        return Some(value);
+/// Starts and stops meters to match the last listing, if they are running.
+/// Must be called with the mainloop locked, or from its thread.
+unsafe fn sync_peak_meters(context: *mut pa_context) {
+    let mut meters = PEAK_METERS.lock().unwrap();
+    let meters = match meters.as_mut() {
+        Some(meters) => meters,
+        None => return,
+    };
+    let targets = PEAK_TARGETS.lock().unwrap();
+
+    let stale: Vec<OutputId> = meters
+        .iter()
+        .filter(|(id, meter)| targets.get(*id) != Some(&meter.target))
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in stale {
+        if let Some(meter) = meters.remove(&id) {
+            meter.disconnect();
+        }
     }
 
-    if let Some(value) = try_get_icon(
-        proplist_ptr,
-        PA_PROP_WINDOW_ICON_NAME_.as_ptr() as *const i8,
-    ) {
-        return Some(value);
+    for (id, target) in targets.iter() {
+        if meters.contains_key(id) {
+            continue;
+        }
+        match PeakMeter::connect(context, id.clone(), *target) {
+            Some(meter) => {
+                meters.insert(id.clone(), meter);
+            }
+            None => {
+                Exception::Misc(format!("Failed to start peak meter for {}.", id)).log_and_ignore()
+            }
+        }
     }
+}
 
-    if let Some(value) = try_get_icon(
-        proplist_ptr,
-        PA_PROP_APPLICATION_ICON_NAME_.as_ptr() as *const i8,
-    ) {
-        return Some(value);
-    };
+#[no_mangle]
+extern "C" fn peak_read_callback(stream: *mut pa_stream, _: usize, userdata: *mut c_void) {
+    let mut data: *const c_void = std::ptr::null();
+    let mut length: usize = 0;
 
-    None
-}
+    unsafe {
+        if pa_stream_peek(stream, &mut data, &mut length) < 0 {
+            return;
+        }
 
-unsafe fn try_get_icon(proplist_ptr: *mut pa_proplist, key: *const c_char) -> Option<*const i8> {
-    let ico_name_ptr = pa_proplist_gets(proplist_ptr, key);
+        if data.is_null() {
+            // A hole in the stream.
+            if length > 0 {
+                pa_stream_drop(stream);
+            }
+            return;
+        }
 
-    if !ico_name_ptr.is_null() {
-        return Some(ico_name_ptr);
+        // Only the latest reading matters.
+        let readings = length / std::mem::size_of::<f32>();
+        let peak = if readings > 0 {
+            *(data as *const f32).add(readings - 1)
+        } else {
+            0.
+        };
+        pa_stream_drop(stream);
+
+        let id = (*(userdata as *const OutputId)).clone();
+        Popout::set_specific_peak(id, peak.clamp(0., 1.));
     }
-    None
 }
 
-fn update_list(
-    userdata: &Arc<GetSinkListUserdata>,
-    name: String,
-    volume: f32,
-    muted: bool,
-    output_id: String,
-    pa_index: u32,
-    icon_name: Option<String>,
-    type_: VolumeType,
-) {
-    let mut list = userdata.list.lock().unwrap();
-    list.push(shared_output_list::Output {
-        name,
-        volume,
-        muted,
-        id: output_id,
-        pa_index: Some(pa_index),
-        icon_name,
-        type_,
-    });
+fn update_list(userdata: &Arc<GetSinkListUserdata>, output: shared_output_list::Output) {
+    userdata.list.lock().unwrap().push(output);
 }
 
 #[no_mangle]
@@ -533,23 +2242,29 @@ pub extern "C" fn context_state_callback(context: *mut pa_context, _: *mut c_voi
     unsafe {
         let state = pa_context_get_state(context);
         if state == PA_CONTEXT_READY {
-            *IN_RECONNECT_LOOP.lock().unwrap() = false;
             pa_context_set_subscribe_callback(
                 context,
                 Some(subscribe_callback),
                 std::ptr::null_mut(),
             );
 
-            let mut flags = PA_SUBSCRIPTION_MASK_SINK;
+            // Server events report default device changes, card events
+            // profile switches.
+            let mut flags =
+                PA_SUBSCRIPTION_MASK_SINK | PA_SUBSCRIPTION_MASK_SERVER | PA_SUBSCRIPTION_MASK_CARD;
 
-            if OPTIONS.show_icons {
+            if OPTIONS.show_streams {
                 flags |= PA_SUBSCRIPTION_MASK_SINK_INPUT;
             }
 
-            if OPTIONS.show_inputs {
+            if OPTIONS.show_inputs || OPTIONS.show_monitors {
                 flags |= PA_SUBSCRIPTION_MASK_SOURCE;
             }
 
+            if OPTIONS.show_inputs {
+                flags |= PA_SUBSCRIPTION_MASK_SOURCE_OUTPUT;
+            }
+
             let op = pa_context_subscribe(context, flags, None, std::ptr::null_mut());
             if op.is_null() {
                 Exception::Misc("PulseAudio context subscription failed".to_string())
@@ -558,44 +2273,187 @@ pub extern "C" fn context_state_callback(context: *mut pa_context, _: *mut c_voi
                 pa_operation_unref(op);
             }
 
-            AUDIO.lock().unwrap().aud.get_outputs(Box::new(
-                |outputs: Vec<shared_output_list::Output>| {
-                    reload_outputs_in_popout(outputs);
-                },
-            ));
+            notify_connection_waiter(true);
         } else if state == PA_CONTEXT_FAILED {
+            if notify_connection_waiter(false) {
+                return;
+            }
             Exception::Misc("Failed to connect to PulseAudio (PA_CONTEXT_FAILED).".to_string())
                 .log_and_ignore();
-            retry_connection_loop();
+            connection_lost();
         } else if state == PA_CONTEXT_TERMINATED {
+            if notify_connection_waiter(false) {
+                return;
+            }
             Exception::Misc("Disconnected from PulseAudio (PA_CONTEXT_TERMINATED)".to_string())
                 .log_and_ignore();
-            retry_connection_loop();
+            connection_lost();
         }
     }
 }
 
 #[no_mangle]
 pub extern "C" fn subscribe_callback(
-    _: *mut pa_context,
+    context: *mut pa_context,
     event_type: pa_subscription_event_type_t,
-    _: u32,
+    index: u32,
     _: *mut c_void,
 ) {
-    let event_type = event_type & PA_SUBSCRIPTION_EVENT_FACILITY_MASK;
+    let facility = event_type & PA_SUBSCRIPTION_EVENT_FACILITY_MASK;
+    let operation = event_type & PA_SUBSCRIPTION_EVENT_TYPE_MASK;
 
-    if event_type == PA_SUBSCRIPTION_EVENT_SINK
-        || event_type == PA_SUBSCRIPTION_EVENT_SINK_INPUT
-        || event_type == PA_SUBSCRIPTION_EVENT_SOURCE
-    {
-        Popout::handle_callback(|_| {
-            AUDIO.lock().unwrap().aud.get_outputs(Box::new(
-                |outputs: Vec<shared_output_list::Output>| {
-                    sink_change_subscription_event_handler(outputs);
-                },
-            ));
-        });
+    if facility == PA_SUBSCRIPTION_EVENT_CARD {
+        if Popout::ignores_object_event(|_| false) {
+            return;
+        }
+        if operation == PA_SUBSCRIPTION_EVENT_REMOVE {
+            CARDS.lock().unwrap().remove(&index);
+        } else {
+            unsafe { fetch_card(context, index) };
+        }
+        return;
+    }
+
+    // Only the defaults are refetched. This runs on the mainloop's thread,
+    // so it can't go through `AUDIO`, which the UI thread holds while
+    // waiting for the mainloop.
+    if facility == PA_SUBSCRIPTION_EVENT_SERVER {
+        if Popout::ignores_object_event(|_| false) {
+            return;
+        }
+        let op = unsafe {
+            pa_context_get_server_info(context, Some(server_changed_callback), std::ptr::null_mut())
+        };
+        if op.is_null() {
+            Exception::Misc("Failed to get PA server info.".to_string()).log_and_ignore();
+        } else {
+            unsafe { pa_operation_unref(op) };
+        }
+        return;
+    }
+
+    let type_ = if facility == PA_SUBSCRIPTION_EVENT_SINK {
+        VolumeType::Sink
+    } else if facility == PA_SUBSCRIPTION_EVENT_SOURCE {
+        VolumeType::Input
+    } else if facility == PA_SUBSCRIPTION_EVENT_SINK_INPUT {
+        VolumeType::Stream
+    } else if facility == PA_SUBSCRIPTION_EVENT_SOURCE_OUTPUT {
+        VolumeType::Recording
+    } else {
+        return;
+    };
+
+    // Only a change can be the echo of one made from the popout.
+    let is_echo = |id: &OutputId| {
+        operation == PA_SUBSCRIPTION_EVENT_CHANGE && id.type_ == type_ && id.index == Some(index)
+    };
+    if Popout::ignores_object_event(is_echo) {
+        return;
+    }
+    unsafe {
+        if operation == PA_SUBSCRIPTION_EVENT_REMOVE {
+            forget_object(context, type_, index);
+        } else {
+            fetch_object(context, type_, index);
+        }
+    }
+}
+
+/// Fetches a single object that was added or changed, and merges it into
+/// the outputs. Must be called with the mainloop locked, or from its
+/// thread.
+unsafe fn fetch_object(context: *mut pa_context, type_: VolumeType, index: u32) {
+    let userdata = GetSinkListUserdata::new(merge_fetched(context), 1, true);
+
+    let raw_userdata = Arc::into_raw(userdata) as *mut c_void;
+
+    let op = match type_ {
+        VolumeType::Sink => pa_context_get_sink_info_by_index(
+            context,
+            index,
+            Some(sink_info_callback),
+            raw_userdata,
+        ),
+        VolumeType::Input => pa_context_get_source_info_by_index(
+            context,
+            index,
+            Some(source_info_callback),
+            raw_userdata,
+        ),
+        VolumeType::Stream => pa_context_get_sink_input_info(
+            context,
+            index,
+            Some(sink_input_info_callback),
+            raw_userdata,
+        ),
+        VolumeType::Recording => pa_context_get_source_output_info(
+            context,
+            index,
+            Some(source_output_info_callback),
+            raw_userdata,
+        ),
+    };
+
+    check_listing(op, raw_userdata, &format!("{:?} {}", type_, index));
+}
+
+/// Fetches a card whose profiles changed, updating the devices on it. Must
+/// be called with the mainloop locked, or from its thread.
+unsafe fn fetch_card(context: *mut pa_context, index: u32) {
+    let userdata = GetSinkListUserdata::new(merge_fetched(context), 1, true);
+
+    let raw_userdata = Arc::into_raw(userdata) as *mut c_void;
+    let op =
+        pa_context_get_card_info_by_index(context, index, Some(card_info_callback), raw_userdata);
+    check_listing(op, raw_userdata, &format!("card {}", index));
+}
+
+/// Applies the outputs a fetch of single objects returned.
+fn merge_fetched(context: *mut pa_context) -> Box<dyn Fn(Vec<shared_output_list::Output>)> {
+    Box::new(move |outputs: Vec<shared_output_list::Output>| {
+        for output in outputs {
+            output_changed(output);
+        }
+        unsafe { sync_peak_meters(context) };
+    })
+}
+
+/// Drops an object the server removed. Called from the mainloop's thread.
+unsafe fn forget_object(context: *mut pa_context, type_: VolumeType, index: u32) {
+    match type_ {
+        VolumeType::Sink => {
+            SINK_MONITORS.lock().unwrap().remove(&index);
+        }
+        // The input's row is going, and what is attached to it with it.
+        VolumeType::Input => {
+            let attached = take_modules(|module| {
+                module
+                    .input()
+                    .is_some_and(|source| source.index == Some(index))
+            });
+            for (module_index, module) in attached {
+                send_previous_defaults(context, &module);
+                let op =
+                    pa_context_unload_module(context, module_index, None, std::ptr::null_mut());
+                if !op.is_null() {
+                    pa_operation_unref(op);
+                }
+            }
+        }
+        _ => {}
     }
+
+    let id = match shared_output_list::find_by_index(&type_, index) {
+        Some(id) => id,
+        None => return,
+    };
+
+    PA_CVOLUMES.lock().unwrap().remove(&id);
+    PEAK_TARGETS.lock().unwrap().remove(&id);
+    sync_peak_meters(context);
+
+    output_removed(&id);
 }
 
 #[no_mangle]
@@ -606,68 +2464,553 @@ pub extern "C" fn server_info_callback(
 ) {
     unsafe {
         let default_sink_name = (*server_info).default_sink_name;
-        let default_sink_name = std::ffi::CStr::from_ptr(default_sink_name);
-        let default_sink_name = default_sink_name.to_string_lossy().to_string();
-        set_default_output(default_sink_name);
+        if !default_sink_name.is_null() {
+            let default_sink_name = CStr::from_ptr(default_sink_name);
+            let default_sink_name = default_sink_name.to_string_lossy().to_string();
+            // An echo canceller's sink is shown as the output it plays on.
+            set_default_output(
+                echo_cancelled_device(&default_sink_name).unwrap_or(default_sink_name),
+            );
+        }
+
+        let default_source_name = (*server_info).default_source_name;
+        if !default_source_name.is_null() {
+            let default_source_name = CStr::from_ptr(default_source_name)
+                .to_string_lossy()
+                .to_string();
+            // An echo cancelled input is shown as the default itself.
+            set_default_input(
+                echo_cancelled_device(&default_source_name).unwrap_or(default_source_name),
+            );
+        }
+
+        set_server_info(describe_server(&*server_info));
     }
 }
 
-fn sink_change_subscription_event_handler(outputs: Vec<shared_output_list::Output>) {
-    let mut old_outputs = shared_output_list::OUTPUT_LIST.lock().unwrap();
-    if outputs.len() != old_outputs.len() {
-        drop(old_outputs);
-        reload_outputs_in_popout(outputs);
-    } else {
-        for (i, output) in outputs.iter().enumerate() {
-            if output.volume != old_outputs[i].volume {
-                old_outputs[i].volume = output.volume;
-                Popout::set_specific_volume(output.id.clone(), output.volume);
+/// Shows the new defaults after a server event. Full listings show them
+/// along with the outputs.
+#[no_mangle]
+extern "C" fn server_changed_callback(
+    context: *mut pa_context,
+    server_info: *const pa_server_info,
+    userdata: *mut c_void,
+) {
+    server_info_callback(context, server_info, userdata);
+    update_defaults();
+}
 
-                if output.is_default() {
-                    TrayIcon::set_volume(output.volume);
-                }
-            }
-            if output.muted != old_outputs[i].muted {
-                old_outputs[i].muted = output.muted;
-                Popout::set_specific_muted(output.id.clone(), output.muted);
-                if output.is_default() {
-                    TrayIcon::set_muted(output.muted);
-                }
+/// Starts reconnecting on the UI thread. Called from the mainloop's
+/// thread, which can't wait for a new connection.
+fn connection_lost() {
+    idle_add_once(|| {
+        let mut attempts = RECONNECT_ATTEMPTS.lock().unwrap();
+        if attempts.is_some() {
+            return;
+        }
+        *attempts = Some(0);
+        drop(attempts);
+
+        // Nothing listed so far can be changed any more.
+        PEAK_TARGETS.lock().unwrap().clear();
+        TrayIcon::set_connected(false);
+        Popout::show_reconnecting();
+
+        schedule_reconnect();
+    });
+}
+
+fn schedule_reconnect() {
+    let attempts = RECONNECT_ATTEMPTS.lock().unwrap().unwrap_or(0);
+
+    timeout_add_once(reconnect_delay(attempts), || {
+        // Connecting waits for the server, so it is kept off the UI thread.
+        thread::spawn(|| {
+            let result = Pulse::new();
+            idle_add_once(move || reconnected(result));
+        });
+    });
+}
+
+/// Doubles the wait after each failed attempt, up to a minute.
+fn reconnect_delay(attempts: u32) -> Duration {
+    Duration::from_secs(1 << attempts.min(6)).min(MAX_RECONNECT_DELAY)
+}
+
+fn reconnected(result: Result<Pulse, Exception>) {
+    let pulse = match result {
+        Ok(pulse) => pulse,
+        Err(e) => {
+            e.log_and_ignore();
+            if let Some(attempts) = RECONNECT_ATTEMPTS.lock().unwrap().as_mut() {
+                *attempts += 1;
             }
+            schedule_reconnect();
+            return;
         }
+    };
+
+    *RECONNECT_ATTEMPTS.lock().unwrap() = None;
+    CARDS.lock().unwrap().clear();
+    // Asked before listing, so the list is answered with what is left.
+    unsafe {
+        pa_threaded_mainloop_lock(pulse.mainloop);
+        recheck_loaded_modules(pulse.context);
+        pa_threaded_mainloop_unlock(pulse.mainloop);
     }
-}
+    let meters_running = PEAK_METERS.lock().unwrap().is_some();
 
-fn retry_connection_loop() {
-    if *IN_RECONNECT_LOOP.lock().unwrap() {
-        return;
+    // The old connection is torn down outside the lock, as its mainloop's
+    // callbacks take it too.
+    let old = std::mem::replace(&mut AUDIO.lock().unwrap().aud, Arc::new(pulse));
+    drop(old);
+
+    TrayIcon::set_connected(true);
+
+    let audio = AUDIO.lock().unwrap();
+    audio.aud.get_outputs(Box::new(reload_outputs_in_popout));
+    if meters_running {
+        audio.aud.start_peak_meters();
     }
-    *IN_RECONNECT_LOOP.lock().unwrap() = true;
-    let mut loop_counter = 0;
-    while loop_counter < 40 {
-        println!("Retrying connection...");
-        *AUDIO.lock().unwrap() = get_audio();
-        std::thread::sleep(std::time::Duration::from_secs(5));
-        if !*IN_RECONNECT_LOOP.lock().unwrap() {
-            return;
+}
+
+/// Reports the outcome of a connection attempt to a waiting `Pulse::new`.
+/// Returns false if nothing was waiting.
+fn notify_connection_waiter(connected: bool) -> bool {
+    match CONNECTION_WAITER.lock().unwrap().take() {
+        Some(sender) => {
+            let _ = sender.send(connected);
+            true
         }
-        loop_counter += 1;
+        None => false,
     }
-    Exception::Misc("PulseAudio context not found.".to_string()).log_and_exit();
+}
+
+unsafe fn destroy_connection(context: *mut pa_context, mainloop: *mut pa_threaded_mainloop) {
+    pa_threaded_mainloop_lock(mainloop);
+    pa_context_set_state_callback(context, None, std::ptr::null_mut());
+    pa_context_disconnect(context);
+    pa_context_unref(context);
+    pa_threaded_mainloop_unlock(mainloop);
+
+    pa_threaded_mainloop_stop(mainloop);
+    pa_threaded_mainloop_free(mainloop);
 }
 
 impl Drop for Pulse {
     fn drop(&mut self) {
-        self.cleanup();
+        self.disconnect();
     }
 }
 
+/// PA_VOLUME_NORM is 100%. Like pavucontrol, the percentage is on PulseAudio's
+/// cubic scale.
+fn volume_to_percent(volume: pa_volume_t) -> f32 {
+    (volume as f64 / PA_VOLUME_NORM as f64 * 100.) as f32
+}
+
+/// A volume's gain in dB, as PulseAudio works it out. Every backend uses
+/// the same cubic percentages, so this serves them all.
+pub fn volume_to_db(percent: f32) -> f32 {
+    unsafe { pa_sw_volume_to_dB(percent_to_volume(percent)) as f32 }
+}
+
+pub fn volume_from_db(db: f32) -> f32 {
+    volume_to_percent(unsafe { pa_sw_volume_from_dB(db as f64) })
+}
+
+fn percent_to_volume(percent: f32) -> pa_volume_t {
+    (percent.max(0.) as f64 / 100. * PA_VOLUME_NORM as f64).round() as pa_volume_t
+}
+
 fn clamp_volume(vol: f32) -> f32 {
-    if vol > 100. {
-        100.
+    if vol > OPTIONS.max_volume {
+        OPTIONS.max_volume
     } else if vol < 0. {
         0.
     } else {
         vol
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::mock::{output, stereo};
+
+    /// An output as this backend lists it, at `index`.
+    fn listed(name: &str, type_: VolumeType, index: u32) -> shared_output_list::Output {
+        let mut listed = output(name, type_.clone(), 50., false);
+        let name = match type_ {
+            VolumeType::Sink | VolumeType::Input => Some(name.to_string()),
+            VolumeType::Stream | VolumeType::Recording => None,
+        };
+        listed.id = OutputId::new(Backend::Pulse, type_, Some(index), name);
+        listed
+    }
+
+    #[test]
+    fn stream_sinks() {
+        let mut list = vec![
+            listed("speakers", VolumeType::Sink, 0),
+            listed("headphones", VolumeType::Sink, 1),
+            listed("music", VolumeType::Stream, 0),
+            listed("video", VolumeType::Stream, 1),
+        ];
+
+        resolve_stream_sinks(&mut list, &[(0, 1), (1, 7)]);
+
+        assert_eq!(list[2].sink_id, Some(list[1].id.clone()));
+        assert_eq!(list[3].sink_id, None);
+        assert_eq!(list[0].sink_id, None);
+    }
+
+    #[test]
+    fn peak_targets_follow_sinks() {
+        let mut list = vec![
+            listed("speakers", VolumeType::Sink, 1),
+            listed("mic", VolumeType::Input, 2),
+            listed("music", VolumeType::Stream, 3),
+            listed("obs", VolumeType::Recording, 4),
+        ];
+        list[2].sink_id = Some(list[0].id.clone());
+        let [speakers, mic, music, obs] = [0, 1, 2, 3].map(|i| list[i].id.clone());
+
+        let targets = peak_targets(&list, &HashMap::from([(1, 7)]));
+
+        assert_eq!(
+            targets.get(&speakers),
+            Some(&PeakTarget {
+                source: 7,
+                sink_input: None
+            })
+        );
+        assert_eq!(
+            targets.get(&mic),
+            Some(&PeakTarget {
+                source: 2,
+                sink_input: None
+            })
+        );
+        assert_eq!(
+            targets.get(&music),
+            Some(&PeakTarget {
+                source: 7,
+                sink_input: Some(3)
+            })
+        );
+        assert_eq!(targets.get(&obs), None);
+
+        let targets = peak_targets(&list, &HashMap::from([(1, PA_INVALID_INDEX)]));
+        assert_eq!(targets.get(&speakers), None);
+        assert_eq!(targets.get(&music), None);
+    }
+
+    #[test]
+    fn monitors() {
+        let mut list = vec![
+            listed("speakers", VolumeType::Sink, 4),
+            listed("speakers.monitor", VolumeType::Input, 0),
+            listed("mic", VolumeType::Input, 1),
+        ];
+        list[0].name = "Speakers".to_string();
+
+        let monitor = list[1].id.clone();
+        resolve_monitors(&mut list, &[(monitor, 4)]);
+
+        assert_eq!(list[1].name, "Speakers");
+        assert_eq!(list[2].name, "mic");
+    }
+
+    #[test]
+    fn cards() {
+        let mut list = vec![
+            listed("speakers", VolumeType::Sink, 0),
+            listed("mic", VolumeType::Input, 0),
+        ];
+        let card = shared_output_list::Card {
+            id: "pci".to_string(),
+            pa_index: Some(3),
+            profiles: vec![],
+            active_profile: None,
+        };
+
+        let device_cards = [(list[0].id.clone(), 3), (list[1].id.clone(), 4)];
+        resolve_cards(&mut list, std::slice::from_ref(&card), &device_cards);
+
+        assert_eq!(list[0].card, Some(card.clone()));
+        assert_eq!(list[1].card, None);
+
+        let switched = shared_output_list::Card {
+            active_profile: Some("hdmi".to_string()),
+            ..card
+        };
+        assert_eq!(
+            refresh_cards(&mut list, std::slice::from_ref(&switched)),
+            vec![list[0].id.clone()]
+        );
+        assert_eq!(list[0].card, Some(switched));
+        assert_eq!(list[1].card, None);
+    }
+
+    #[test]
+    fn server_description() {
+        let name = CString::new("pulseaudio").unwrap();
+        let version = CString::new("16.1").unwrap();
+        let host = CString::new("workstation").unwrap();
+
+        let mut info: pa_server_info = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { describe_server(&info) }, None);
+
+        info.server_name = name.as_ptr();
+        assert_eq!(
+            unsafe { describe_server(&info) }.as_deref(),
+            Some("pulseaudio")
+        );
+
+        info.server_version = version.as_ptr();
+        info.host_name = host.as_ptr();
+        assert_eq!(
+            unsafe { describe_server(&info) }.as_deref(),
+            Some("pulseaudio 16.1 on workstation")
+        );
+    }
+
+    #[test]
+    fn sink_arguments_are_quoted() {
+        assert_eq!(
+            sink_arguments("Stream mix"),
+            "sink_name=volapplet_stream_mix sink_properties='device.description=\"Stream mix\"'"
+        );
+        assert_eq!(
+            sink_arguments("Bob's \"mix\""),
+            "sink_name=volapplet_bob_s__mix_ sink_properties='device.description=\"Bobs mix\"'"
+        );
+        assert_eq!(
+            combine_sink_arguments("Both", &["usb".to_string(), "bluez".to_string()]),
+            "sink_name=volapplet_both sink_properties='device.description=\"Both\"' slaves=usb,bluez"
+        );
+    }
+
+    #[test]
+    fn loopback_arguments_pin_both_ends() {
+        assert_eq!(
+            loopback_arguments("alsa_input.usb-mic", "alsa_output.speakers", 50),
+            "source=alsa_input.usb-mic sink=alsa_output.speakers latency_msec=50 \
+             source_dont_move=true"
+        );
+    }
+
+    #[test]
+    fn stream_labels_name_the_application() {
+        let firefox = StreamProperties {
+            application: Some("Firefox".to_string()),
+            binary: Some("firefox".to_string()),
+            media_name: Some("YouTube: song title".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            firefox.label("AudioStream".to_string()),
+            (
+                "Firefox".to_string(),
+                Some("YouTube: song title".to_string())
+            )
+        );
+
+        let player = StreamProperties {
+            binary: Some("mpv".to_string()),
+            media_name: Some("song.flac".to_string()),
+            title: Some("Song".to_string()),
+            artist: Some("Artist".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            player.label("Playback".to_string()),
+            ("mpv".to_string(), Some("Artist — Song".to_string()))
+        );
+
+        let generic = StreamProperties {
+            application: Some("Game".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            generic.label("Playback".to_string()),
+            ("Game".to_string(), None)
+        );
+
+        assert_eq!(
+            StreamProperties::default().label("Stream name".to_string()),
+            ("Stream name".to_string(), None)
+        );
+        assert_eq!(
+            StreamProperties::default().label("Playback".to_string()),
+            ("Unknown stream".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn echo_cancel_arguments_name_devices_after_the_input() {
+        assert_eq!(
+            echo_cancel_arguments("alsa_input.mic", "alsa_output.speakers"),
+            "aec_method=webrtc aec_args='noise_suppression=1' source_master=alsa_input.mic \
+             sink_master=alsa_output.speakers source_name=alsa_input.mic.echo_cancel \
+             sink_name=alsa_input.mic.echo_cancel_sink"
+        );
+    }
+
+    #[test]
+    fn modules_are_kept_while_the_server_has_them() {
+        let sink = |name: &str| LoadedModule {
+            name: name.to_string(),
+            arguments: sink_arguments(name),
+            role: ModuleRole::Sink { members: vec![] },
+        };
+        let mut loaded = HashMap::from([(3, sink("Stream mix")), (4, sink("Other"))]);
+
+        forget_unloaded_modules(
+            &mut loaded,
+            &[
+                (1, "".to_string()),
+                (3, sink_arguments("Stream mix")),
+                (4, "sink_name=reused".to_string()),
+            ],
+        );
+
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), vec![&3]);
+    }
+
+    #[test]
+    fn echo_cancellers_restore_the_first_defaults() {
+        // Started on `input` while `previous_default` is the default input.
+        let canceller = |input: &str, previous_default: String| LoadedModule {
+            name: format!("{} with echo cancelled", input),
+            arguments: echo_cancel_arguments(input, "speakers"),
+            role: ModuleRole::EchoCancel {
+                source: output(input, VolumeType::Input, 50., false).id,
+                virtual_source: format!("{}.echo_cancel", input),
+                previous_default,
+                sink: "speakers".to_string(),
+                virtual_sink: format!("{}.echo_cancel_sink", input),
+            },
+        };
+        let mut loaded = HashMap::new();
+
+        let previous = default_before_echo_cancel(&loaded).unwrap_or("headset".to_string());
+        loaded.insert(1, canceller("mic", previous));
+        // The first canceller's input is the default by now.
+        let previous = default_before_echo_cancel(&loaded).unwrap_or("mic".to_string());
+        loaded.insert(2, canceller("webcam", previous));
+
+        let mic = loaded.remove(&1).unwrap();
+        assert_eq!(
+            defaults_after(&mic, &loaded),
+            Some((
+                "webcam.echo_cancel".to_string(),
+                "webcam.echo_cancel_sink".to_string()
+            ))
+        );
+
+        let webcam = loaded.remove(&2).unwrap();
+        assert_eq!(
+            defaults_after(&webcam, &loaded),
+            Some(("headset".to_string(), "speakers".to_string()))
+        );
+    }
+
+    #[test]
+    fn modules_survive_a_reconnect() {
+        let _guard = crate::test_harness::lock_state();
+
+        // Stand-ins for the two contexts, which are only compared.
+        let (mut lost, mut current) = (0u8, 0u8);
+        let lost = &mut lost as *mut u8 as *mut pa_context;
+        let current = &mut current as *mut u8 as *mut pa_context;
+        let operation = |context| PendingOperation {
+            description: "list modules".to_string(),
+            output_id: None,
+            module: None,
+            context,
+            mainloop: std::ptr::null_mut(),
+        };
+
+        let arguments = loopback_arguments("mic", "speakers", 50);
+        LOADED_MODULES.lock().unwrap().insert(
+            7,
+            LoadedModule {
+                name: "mic".to_string(),
+                arguments: arguments.clone(),
+                role: ModuleRole::Loopback {
+                    source: output("mic", VolumeType::Input, 50., false).id,
+                },
+            },
+        );
+        let lost_key = begin_operation(operation(lost));
+        let current_key = begin_operation(operation(current));
+
+        // The new connection finds the module, then the lost one goes.
+        forget_unloaded_modules(&mut LOADED_MODULES.lock().unwrap(), &[(7, arguments)]);
+        assert!(forget_connection(lost).is_empty());
+
+        assert!(LOADED_MODULES.lock().unwrap().remove(&7).is_some());
+        assert!(take_operation(lost_key).is_none());
+        assert!(take_operation(current_key).is_some());
+    }
+
+    #[test]
+    fn reconnect_delay_backs_off() {
+        assert_eq!(reconnect_delay(0), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(1), Duration::from_secs(2));
+        assert_eq!(reconnect_delay(5), Duration::from_secs(32));
+        assert_eq!(reconnect_delay(6), MAX_RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(40), MAX_RECONNECT_DELAY);
+    }
+
+    #[test]
+    fn operations_are_answered_once() {
+        let key = begin_operation(PendingOperation {
+            description: "set volume".to_string(),
+            output_id: None,
+            module: None,
+            context: std::ptr::null_mut(),
+            mainloop: std::ptr::null_mut(),
+        });
+
+        assert_eq!(
+            take_operation(key).map(|operation| operation.description),
+            Some("set volume".to_string())
+        );
+        // A late answer after the timeout finds nothing to do.
+        assert!(take_operation(key).is_none());
+    }
+
+    #[test]
+    fn channels() {
+        let mut map: pa_channel_map = unsafe { std::mem::zeroed() };
+        map.channels = 3;
+        map.map[0] = PA_CHANNEL_POSITION_FRONT_LEFT;
+        map.map[1] = PA_CHANNEL_POSITION_FRONT_RIGHT;
+        map.map[2] = PA_CHANNEL_POSITION_LFE;
+
+        let mut volume: pa_cvolume = unsafe { std::mem::zeroed() };
+        volume.channels = 3;
+        volume.values[0] = percent_to_volume(80.);
+        volume.values[1] = percent_to_volume(40.);
+        volume.values[2] = percent_to_volume(10.);
+
+        let channels = unsafe { read_channels(&volume, &map) };
+
+        assert_eq!(&channels[..2], &stereo(80., 40.)[..]);
+        assert_eq!(channels[2].position, ChannelPosition::Center);
+    }
+
+    #[test]
+    fn normalisation() {
+        assert_eq!(percent_to_volume(100.), PA_VOLUME_NORM);
+        assert_eq!(volume_to_percent(PA_VOLUME_NORM / 2), 50.);
+        assert_eq!(percent_to_volume(-3.), 0);
+
+        assert!(volume_to_db(100.).abs() < 0.001);
+        assert!((volume_to_db(50.) + 18.06).abs() < 0.01);
+        assert!((volume_from_db(volume_to_db(50.)) - 50.).abs() < 0.01);
+    }
+}
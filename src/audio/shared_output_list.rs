@@ -1,28 +1,141 @@
-use std::sync::Mutex;
+use std::{fmt, sync::Mutex};
 
 use once_cell::sync::Lazy;
 
-use crate::exception::Exception;
+use crate::{exception::Exception, options::Backend};
 
 pub static OUTPUT_LIST: Lazy<Mutex<Vec<Output>>> = Lazy::new(|| Mutex::new(vec![]));
 pub static DEFAULT_OUTPUT_ID: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new("".to_string()));
+pub static DEFAULT_INPUT_ID: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new("".to_string()));
+/// Which server the backend is connected to, if it reports one.
+pub static SERVER_INFO: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
 
 #[derive(Clone)]
 pub struct Output {
     pub name: String,
     pub volume: f32,
     pub muted: bool,
+    pub id: OutputId,
+    pub icon_name: Option<String>,
+    /// The sink a stream is playing on, if known.
+    pub sink_id: Option<OutputId>,
+    /// Per-channel volumes, empty if the backend doesn't report them.
+    pub channels: Vec<ChannelVolume>,
+    /// The sound card a device belongs to, if it has profiles.
+    pub card: Option<Card>,
+    pub ports: Vec<Port>,
+    pub active_port: Option<String>,
+    /// What kind of input, or of virtual sink, this is, if the backend
+    /// can tell.
+    pub source_type: Option<SourceType>,
+    /// Created by the applet, which can remove it again.
+    pub removable: bool,
+    /// Names of the devices a combined sink plays on.
+    pub members: Vec<String>,
+    /// Whether an input is played back live, to hear it.
+    pub listening: bool,
+    /// Whether an input has echo and noise cancelled. What is cancelled is
+    /// the default input in its place.
+    pub echo_cancelled: bool,
+    /// A second line under the name, such as what a stream is playing.
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceType {
+    Hardware,
+    Virtual,
+    /// Captures what a sink plays.
+    Monitor,
+}
+
+impl SourceType {
+    pub fn label(&self) -> &'static str {
+        match self {
+            SourceType::Hardware => "Hardware",
+            SourceType::Virtual => "Virtual",
+            SourceType::Monitor => "Monitor",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Port {
+    pub name: String,
+    pub description: String,
+    /// Whether something is plugged in, `None` if it can't be detected.
+    pub available: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Card {
     pub id: String,
     pub pa_index: Option<u32>,
-    pub icon_name: Option<String>,
+    /// Names and descriptions of the profiles that can be switched to.
+    pub profiles: Vec<(String, String)>,
+    pub active_profile: Option<String>,
+}
+
+/// Which side a channel plays on, as far as balance is concerned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelPosition {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelVolume {
+    pub position: ChannelPosition,
+    pub volume: f32,
+}
+
+/// Identifies an output. Streams can share a name, so they are told apart
+/// by the index the server gave them; devices also carry the name the
+/// server knows them by.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutputId {
+    pub backend: Backend,
     pub type_: VolumeType,
+    pub index: Option<u32>,
+    pub name: Option<String>,
 }
 
-#[derive(Clone)]
+impl OutputId {
+    pub fn new(
+        backend: Backend,
+        type_: VolumeType,
+        index: Option<u32>,
+        name: Option<String>,
+    ) -> OutputId {
+        OutputId {
+            backend,
+            type_,
+            index,
+            name,
+        }
+    }
+}
+
+/// A key unique among the current outputs, for widgets that only take
+/// strings.
+impl fmt::Display for OutputId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.index) {
+            (Some(name), _) => write!(f, "{:?}:{}", self.type_, name),
+            (None, Some(index)) => write!(f, "{:?}#{}", self.type_, index),
+            (None, None) => write!(f, "{:?}", self.type_),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum VolumeType {
     Sink,
     Stream,
     Input,
+    /// A stream recording from an input.
+    Recording,
 }
 
 pub fn get_output_list() -> Vec<Output> {
@@ -30,98 +143,298 @@ pub fn get_output_list() -> Vec<Output> {
     output_list.clone()
 }
 
-fn is_default_output(output_id: &String) -> bool {
-    *output_id == DEFAULT_OUTPUT_ID.lock().unwrap().to_string()
+/// Replaces the output with the same id, or adds it after the last one of
+/// its type.
+pub fn upsert(list: &mut Vec<Output>, output: Output) {
+    if let Some(old) = list.iter_mut().find(|old| old.id == output.id) {
+        *old = output;
+        return;
+    }
+
+    let position = list
+        .iter()
+        .rposition(|old| old.id.type_ == output.id.type_)
+        .map_or(list.len(), |i| i + 1);
+    list.insert(position, output);
+}
+
+/// The id of the output a backend knows by `index`.
+pub fn find_by_index(type_: &VolumeType, index: u32) -> Option<OutputId> {
+    let output_list = OUTPUT_LIST.lock().unwrap();
+
+    output_list
+        .iter()
+        .find(|output| output.id.type_ == *type_ && output.id.index == Some(index))
+        .map(|output| output.id.clone())
+}
+
+/// The id of the output shown under `key`, see `OutputId`'s `Display`.
+pub fn find_by_key(key: &str) -> Option<OutputId> {
+    let output_list = OUTPUT_LIST.lock().unwrap();
+
+    output_list
+        .iter()
+        .find(|output| output.id.to_string() == key)
+        .map(|output| output.id.clone())
+}
+
+/// Defaults are tracked by the name the server uses.
+pub fn is_default_output(output_id: &OutputId) -> bool {
+    output_id.name.as_deref() == Some(DEFAULT_OUTPUT_ID.lock().unwrap().as_str())
+}
+
+pub fn is_default_input(output_id: &OutputId) -> bool {
+    output_id.name.as_deref() == Some(DEFAULT_INPUT_ID.lock().unwrap().as_str())
 }
 
 impl Output {
     pub fn is_default(&self) -> bool {
         is_default_output(&self.id)
     }
+
+    /// Whether this is the default device of its kind. Streams never are.
+    pub fn is_default_device(&self) -> bool {
+        match self.id.type_ {
+            VolumeType::Sink => self.is_default(),
+            VolumeType::Input => is_default_input(&self.id),
+            VolumeType::Stream | VolumeType::Recording => false,
+        }
+    }
 }
 
 pub fn set_default_output(output_id: String) {
     *DEFAULT_OUTPUT_ID.lock().unwrap() = output_id;
 }
 
-pub fn get_pa_index(output_id: &String) -> Option<u32> {
+pub fn set_default_input(output_id: String) {
+    *DEFAULT_INPUT_ID.lock().unwrap() = output_id;
+}
+
+pub fn set_server_info(info: Option<String>) {
+    *SERVER_INFO.lock().unwrap() = info;
+}
+
+pub fn get_server_info() -> Option<String> {
+    SERVER_INFO.lock().unwrap().clone()
+}
+
+/// The server index of an output that is still listed.
+pub fn get_pa_index(output_id: &OutputId) -> Option<u32> {
     let output_list = OUTPUT_LIST.lock().unwrap();
 
     for output in output_list.iter() {
         if output.id == *output_id {
-            return output.pa_index;
+            return output.id.index;
         }
     }
     None
 }
 
-pub fn type_of(output_id: &String) -> VolumeType {
+pub fn get_default_output() -> Result<Output, Exception> {
+    let output_list = OUTPUT_LIST.lock().unwrap();
+
+    for output in output_list.iter() {
+        if matches!(output.id.type_, VolumeType::Sink) && output.is_default() {
+            return Ok(output.clone());
+        }
+    }
+    Err(Exception::Misc("No default output found".to_string()))
+}
+
+pub fn get_stored_volume(output_id: &OutputId) -> f32 {
     let output_list = OUTPUT_LIST.lock().unwrap();
 
     for output in output_list.iter() {
         if output.id == *output_id {
-            return output.type_.clone();
+            return output.volume;
         }
     }
-    VolumeType::Sink
+    0.
 }
 
-pub fn get_default_output() -> Result<Output, Exception> {
+pub fn get_stored_channels(output_id: &OutputId) -> Vec<ChannelVolume> {
     let output_list = OUTPUT_LIST.lock().unwrap();
 
     for output in output_list.iter() {
-        if output.id == *DEFAULT_OUTPUT_ID.lock().unwrap() {
-            return Ok(output.clone());
+        if output.id == *output_id {
+            return output.channels.clone();
+        }
+    }
+    vec![]
+}
+
+pub fn set_stored_channels(output_id: &OutputId, channels: Vec<ChannelVolume>) {
+    let mut output_list = OUTPUT_LIST.lock().unwrap();
+
+    for output in output_list.iter_mut() {
+        if output.id == *output_id {
+            output.channels = channels;
+            return;
         }
     }
-    Err(Exception::Misc("No default output found".to_string()))
 }
 
-pub fn get_stored_volume(output_id: &String) -> f32 {
+pub fn get_card(card_id: &String) -> Option<Card> {
+    let output_list = OUTPUT_LIST.lock().unwrap();
+
+    output_list
+        .iter()
+        .filter_map(|output| output.card.as_ref())
+        .find(|card| card.id == *card_id)
+        .cloned()
+}
+
+pub fn get_active_port(output_id: &OutputId) -> Option<String> {
     let output_list = OUTPUT_LIST.lock().unwrap();
 
     for output in output_list.iter() {
         if output.id == *output_id {
-            return output.volume;
+            return output.active_port.clone();
         }
     }
-    0.
+    None
+}
+
+pub fn set_active_port(output_id: &OutputId, port: String) {
+    let mut output_list = OUTPUT_LIST.lock().unwrap();
+
+    for output in output_list.iter_mut() {
+        if output.id == *output_id {
+            output.active_port = Some(port);
+            return;
+        }
+    }
+}
+
+/// Records a profile switch on every device of the card.
+pub fn set_active_profile(card_id: &str, profile: &str) {
+    let mut output_list = OUTPUT_LIST.lock().unwrap();
+
+    for card in output_list
+        .iter_mut()
+        .filter_map(|output| output.card.as_mut())
+    {
+        if card.id == card_id {
+            card.active_profile = Some(profile.to_string());
+        }
+    }
+}
+
+/// Whether `channels` has both a left and a right side to balance.
+pub fn has_balance(channels: &[ChannelVolume]) -> bool {
+    side_volumes(channels).is_some()
+}
+
+/// The balance from -1 (left only) to 1 (right only), computed the same
+/// way as `pa_cvolume_get_balance`.
+pub fn get_balance(channels: &[ChannelVolume]) -> f32 {
+    match side_volumes(channels) {
+        Some((left, right)) if left > right => right / left - 1.,
+        Some((left, right)) if right > left => 1. - left / right,
+        _ => 0.,
+    }
+}
+
+/// Moves the quieter side so `channels` reach `balance`, leaving the
+/// louder side where it is.
+pub fn set_balance(channels: &[ChannelVolume], balance: f32) -> Vec<ChannelVolume> {
+    let (left, right) = match side_volumes(channels) {
+        Some(sides) => sides,
+        None => return channels.to_vec(),
+    };
+
+    let max = left.max(right);
+    let balance = balance.clamp(-1., 1.);
+    let (new_left, new_right) = if balance <= 0. {
+        (max, max * (1. + balance))
+    } else {
+        (max * (1. - balance), max)
+    };
+
+    channels
+        .iter()
+        .map(|channel| ChannelVolume {
+            position: channel.position,
+            volume: match channel.position {
+                ChannelPosition::Left => rescale(channel.volume, left, new_left),
+                ChannelPosition::Right => rescale(channel.volume, right, new_right),
+                ChannelPosition::Center => channel.volume,
+            },
+        })
+        .collect()
+}
+
+/// Scales `channels` so the loudest is at `volume`, keeping their ratios
+/// like `pa_cvolume_scale`.
+pub fn scale_channels(channels: &[ChannelVolume], volume: f32) -> Vec<ChannelVolume> {
+    let max = max_volume(channels);
+
+    channels
+        .iter()
+        .map(|channel| ChannelVolume {
+            position: channel.position,
+            volume: rescale(channel.volume, max, volume),
+        })
+        .collect()
+}
+
+pub fn max_volume(channels: &[ChannelVolume]) -> f32 {
+    channels
+        .iter()
+        .map(|channel| channel.volume)
+        .fold(0., f32::max)
+}
+
+/// Average volumes of the left and right channels, if there are both.
+fn side_volumes(channels: &[ChannelVolume]) -> Option<(f32, f32)> {
+    let average = |position| {
+        let volumes: Vec<f32> = channels
+            .iter()
+            .filter(|channel| channel.position == position)
+            .map(|channel| channel.volume)
+            .collect();
+
+        if volumes.is_empty() {
+            None
+        } else {
+            Some(volumes.iter().sum::<f32>() / volumes.len() as f32)
+        }
+    };
+
+    Some((
+        average(ChannelPosition::Left)?,
+        average(ChannelPosition::Right)?,
+    ))
+}
+
+fn rescale(volume: f32, from: f32, to: f32) -> f32 {
+    if from == 0. {
+        to
+    } else {
+        volume * to / from
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio::mock::{output, stereo};
 
     #[test]
     fn output_list() {
+        let _guard = crate::test_harness::lock_state();
+
+        // Named like a server's outputs, with indices as their ids.
+        let labelled = |id: &str, name: &str, type_, volume, muted| Output {
+            name: name.to_string(),
+            ..output(id, type_, volume, muted)
+        };
+
         let mut list = OUTPUT_LIST.lock().unwrap();
-        list.push(Output {
-            name: "Headphones".to_string(),
-            volume: 23.0,
-            muted: false,
-            id: "1".to_string(),
-            pa_index: None,
-            icon_name: None,
-            type_: VolumeType::Sink,
-        });
-        list.push(Output {
-            name: "Speakers".to_string(),
-            volume: 77.0,
-            muted: true,
-            id: "2".to_string(),
-            pa_index: None,
-            icon_name: None,
-            type_: VolumeType::Sink,
-        });
-        list.push(Output {
-            name: "Microphone".to_string(),
-            volume: 22.0,
-            muted: false,
-            id: "3".to_string(),
-            pa_index: None,
-            icon_name: None,
-            type_: VolumeType::Input,
-        });
+        list.clear();
+        list.push(labelled("1", "Headphones", VolumeType::Sink, 23.0, false));
+        list.push(labelled("2", "Speakers", VolumeType::Sink, 77.0, true));
+        list.push(labelled("3", "Microphone", VolumeType::Input, 22.0, false));
         drop(list);
 
         assert_eq!(get_output_list().len(), 3);
@@ -133,6 +446,57 @@ mod tests {
         assert_eq!(default.name, "Speakers");
         assert_eq!(default.volume, 77.0);
         assert_eq!(default.muted, true);
-        assert_eq!(default.id, "2");
+        assert_eq!(default.id.name.as_deref(), Some("2"));
+
+        set_default_input("3".to_string());
+
+        let outputs = get_output_list();
+        assert!(outputs[2].is_default_device());
+        assert!(!outputs[2].is_default());
+        assert!(outputs[1].is_default_device());
+        assert!(!outputs[0].is_default_device());
+    }
+
+    #[test]
+    fn upserts_keep_types_together() {
+        let mut list = vec![
+            output("speakers", VolumeType::Sink, 50., false),
+            output("mic", VolumeType::Input, 50., false),
+            output("music", VolumeType::Stream, 50., false),
+        ];
+
+        upsert(
+            &mut list,
+            output("headphones", VolumeType::Sink, 30., false),
+        );
+        upsert(&mut list, output("mic", VolumeType::Input, 80., false));
+        upsert(&mut list, output("obs", VolumeType::Recording, 50., false));
+
+        let ids: Vec<&str> = list
+            .iter()
+            .map(|output| output.id.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["speakers", "headphones", "mic", "music", "obs"]);
+        assert_eq!(list[2].volume, 80.);
+    }
+
+    #[test]
+    fn balance() {
+        assert_eq!(get_balance(&stereo(50., 50.)), 0.);
+        assert_eq!(get_balance(&stereo(80., 40.)), -0.5);
+        assert_eq!(get_balance(&stereo(20., 80.)), 0.75);
+        assert!(!has_balance(&stereo(50., 50.)[..1]));
+        assert_eq!(get_balance(&stereo(50., 50.)[..1]), 0.);
+
+        assert_eq!(set_balance(&stereo(60., 60.), -0.5), stereo(60., 30.));
+        assert_eq!(set_balance(&stereo(30., 60.), 0.), stereo(60., 60.));
+        assert_eq!(set_balance(&stereo(0., 0.), 1.), stereo(0., 0.));
+    }
+
+    #[test]
+    fn scaling_keeps_ratios() {
+        assert_eq!(scale_channels(&stereo(80., 40.), 40.), stereo(40., 20.));
+        assert_eq!(scale_channels(&stereo(0., 0.), 30.), stereo(30., 30.));
+        assert_eq!(max_volume(&stereo(10., 70.)), 70.);
     }
 }
@@ -1,20 +1,46 @@
 use std::rc::Rc;
 
 use gtk::{
-    glib,
+    gdk::{DragAction, ModifierType},
+    glib::{self, StaticType},
+    prelude::{Cast, GtkListStoreExtManual, WidgetExtManual},
     traits::{
-        ButtonExt, ContainerExt, GridExt, IconThemeExt, LabelExt, RangeExt, ScaleExt, WidgetExt,
+        BoxExt, ButtonExt, CellLayoutExt, ComboBoxExt, ContainerExt, GridExt, IconThemeExt,
+        LabelExt, LevelBarExt, RadioButtonExt, RangeExt, ScaleExt, StyleContextExt,
+        ToggleButtonExt, WidgetExt,
     },
+    DestDefaults, TargetEntry, TargetFlags,
 };
 
-use crate::{audio::shared_output_list::VolumeType, options::OPTIONS};
+use crate::{
+    audio::shared_output_list::{Port, VolumeType},
+    options::OPTIONS,
+};
 
 unsafe impl Sync for VolumeSlider {}
 unsafe impl Send for VolumeSlider {}
 pub struct VolumeSlider {
     volume_label: gtk::Label,
     volume_slider: gtk::Scale,
+    /// Hidden until the backend reports a level.
+    peak_meter: gtk::LevelBar,
     mute_button: gtk::Button,
+    grid: gtk::Grid,
+    main_container: gtk::Box,
+    name_label: Option<gtk::Label>,
+    caption: Option<gtk::Label>,
+    /// Wraps the whole row so it can take part in drag and drop.
+    root: gtk::EventBox,
+    default_selector: Option<gtk::RadioButton>,
+    sink_chooser: Option<gtk::ComboBox>,
+    profile_chooser: Option<gtk::ComboBox>,
+    port_chooser: Option<gtk::ComboBox>,
+    balance_slider: Option<gtk::Scale>,
+    remove_button: Option<gtk::Button>,
+    listen_toggle: Option<gtk::ToggleButton>,
+    echo_cancel_toggle: Option<gtk::CheckButton>,
+    /// Shown under the name while echo is cancelled.
+    echo_cancel_badge: Option<gtk::Label>,
 }
 
 impl VolumeSlider {
@@ -30,20 +56,34 @@ impl VolumeSlider {
     ) -> VolumeSlider {
         let main_container = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
-        if let Some(label_text) = &label {
+        let name_label = label.map(|label_text| {
             let label = gtk::Label::builder()
-                .label(&substring_name(label_text.clone()))
                 .halign(gtk::Align::Start)
                 .valign(gtk::Align::Start)
                 .build();
+            set_truncated_text(&label, &label_text);
             main_container.add(&label);
-        }
+            label
+        });
 
-        let volume_slider = gtk::Scale::with_range(gtk::Orientation::Horizontal, 0.0, 100.0, 1.0);
+        let scale = OPTIONS.volume_scale;
+        let volume_slider = gtk::Scale::with_range(
+            gtk::Orientation::Horizontal,
+            0.0,
+            scale.to_slider(OPTIONS.max_volume) as f64,
+            1.0,
+        );
         volume_slider.set_draw_value(false);
-        volume_slider.set_value(start_value as f64);
+        if OPTIONS.max_volume > 100. {
+            volume_slider.add_mark(
+                scale.to_slider(100.) as f64,
+                gtk::PositionType::Bottom,
+                None,
+            );
+        }
+        volume_slider.set_value(scale.to_slider(start_value) as f64);
         volume_slider.connect_change_value(move |_, _, d: f64| -> glib::signal::Inhibit {
-            on_change_vol(d as f32);
+            on_change_vol(scale.from_slider(d as f32));
             gtk::Inhibit(false)
         });
 
@@ -77,30 +117,360 @@ impl VolumeSlider {
             3,
             3,
         );
+        let peak_meter = gtk::LevelBar::for_interval(0., 1.);
+        peak_meter.set_no_show_all(true);
+        grid.attach_next_to(
+            &peak_meter,
+            Some(&volume_slider),
+            gtk::PositionType::Bottom,
+            26,
+            1,
+        );
+
         main_container.add(&grid);
 
+        let root = gtk::EventBox::new();
+
         if OPTIONS.show_icons {
             let outer_grid = gtk::Grid::new();
             outer_grid.set_column_spacing(10);
             let icon = get_icon(&type_, icon_name);
             outer_grid.add(&icon);
             outer_grid.attach_next_to(&main_container, Some(&icon), gtk::PositionType::Right, 3, 3);
-            container.add(&outer_grid);
+            root.add(&outer_grid);
         } else {
-            container.add(&main_container);
+            root.add(&main_container);
         }
 
+        container.add(&root);
+
         let ret = VolumeSlider {
             volume_label,
             volume_slider,
+            peak_meter,
             mute_button,
+            grid,
+            main_container,
+            name_label,
+            caption: None,
+            root,
+            default_selector: None,
+            sink_chooser: None,
+            profile_chooser: None,
+            port_chooser: None,
+            balance_slider: None,
+            remove_button: None,
+            listen_toggle: None,
+            echo_cancel_toggle: None,
+            echo_cancel_badge: None,
         };
         ret.set_grayed_out_slider(muted);
         ret
     }
 
+    /// Takes the row out of the popout.
+    pub fn remove(&self) {
+        if let Some(parent) = self.root.parent() {
+            if let Ok(parent) = parent.downcast::<gtk::Container>() {
+                parent.remove(&self.root);
+            }
+        }
+    }
+
+    /// Shows the current peak level, as an amplitude between 0 and 1.
+    pub fn set_peak(&self, peak: f32) {
+        self.peak_meter.set_value(peak as f64);
+        self.peak_meter.show();
+    }
+
+    /// Adds a dimmed line of text under the name.
+    pub fn add_caption(&mut self, text: &str) {
+        let caption = gtk::Label::builder().halign(gtk::Align::Start).build();
+        set_truncated_text(&caption, text);
+        caption.style_context().add_class("dim-label");
+
+        self.main_container.add(&caption);
+        self.main_container
+            .reorder_child(&caption, self.name_label.is_some() as i32);
+
+        self.caption = Some(caption);
+    }
+
+    /// Replaces the name and the caption under it, e.g. when a stream moves
+    /// on to another song.
+    pub fn set_label(&mut self, name: &str, caption: Option<&str>) {
+        if let Some(name_label) = &self.name_label {
+            set_truncated_text(name_label, name);
+        }
+
+        match (&self.caption, caption) {
+            (Some(label), Some(text)) => set_truncated_text(label, text),
+            (Some(label), None) => {
+                self.main_container.remove(label);
+                self.caption = None;
+            }
+            (None, Some(text)) => {
+                self.add_caption(text);
+                if let Some(label) = &self.caption {
+                    label.show();
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Adds a radio button for making this the default device. Buttons in
+    /// the same `group` are mutually exclusive.
+    pub fn add_default_selector(
+        &mut self,
+        group: Option<&gtk::RadioButton>,
+        active: bool,
+        on_select: Rc<dyn Fn() + 'static>,
+    ) -> gtk::RadioButton {
+        let button = gtk::RadioButton::new();
+        if group.is_some() {
+            button.join_group(group);
+        }
+        button.set_active(active);
+        button.set_tooltip_text(Some("Use as default"));
+        button.connect_toggled(move |button| {
+            if button.is_active() {
+                on_select();
+            }
+        });
+
+        self.grid.attach_next_to(
+            &button,
+            Some(&self.volume_label),
+            gtk::PositionType::Left,
+            1,
+            3,
+        );
+
+        self.default_selector = Some(button.clone());
+        button
+    }
+
+    pub fn set_default_selected(&self) {
+        if let Some(button) = &self.default_selector {
+            button.set_active(true);
+        }
+    }
+
+    /// Adds a toggle for hearing an input live.
+    pub fn add_listen_toggle(&mut self, active: bool, on_toggle: Rc<dyn Fn(bool) + 'static>) {
+        let toggle = gtk::ToggleButton::new();
+        toggle.set_image(Some(&gtk::Image::from_icon_name(
+            Some("audio-headphones-symbolic"),
+            gtk::IconSize::Button,
+        )));
+        toggle.set_active(active);
+        toggle.set_tooltip_text(Some("Monitor: hear this input live"));
+        toggle.connect_toggled(move |toggle| {
+            on_toggle(toggle.is_active());
+        });
+
+        self.grid.attach_next_to(
+            &toggle,
+            Some(&self.mute_button),
+            gtk::PositionType::Right,
+            3,
+            3,
+        );
+
+        self.listen_toggle = Some(toggle);
+    }
+
+    pub fn set_listening(&self, listening: bool) {
+        if let Some(toggle) = &self.listen_toggle {
+            toggle.set_active(listening);
+        }
+    }
+
+    /// Adds a check box for cancelling echo and noise on an input, with a
+    /// badge under the name while it is on.
+    pub fn add_echo_cancel_toggle(&mut self, active: bool, on_toggle: Rc<dyn Fn(bool) + 'static>) {
+        let badge = gtk::Label::builder()
+            .label("Echo cancelled")
+            .halign(gtk::Align::Start)
+            .build();
+        badge.style_context().add_class("dim-label");
+        badge.set_no_show_all(true);
+        badge.set_visible(active);
+        self.main_container.add(&badge);
+        self.main_container.reorder_child(
+            &badge,
+            self.name_label.is_some() as i32 + self.caption.is_some() as i32,
+        );
+
+        let toggle = gtk::CheckButton::with_label("Cancel echo and noise");
+        toggle.set_active(active);
+        toggle.set_tooltip_text(Some("Uses the cancelled input as the default while on"));
+        toggle.connect_toggled(move |toggle| {
+            on_toggle(toggle.is_active());
+        });
+        self.main_container.add(&toggle);
+
+        self.echo_cancel_toggle = Some(toggle);
+        self.echo_cancel_badge = Some(badge);
+    }
+
+    pub fn set_echo_cancelled(&self, echo_cancelled: bool) {
+        if let Some(badge) = &self.echo_cancel_badge {
+            badge.set_visible(echo_cancelled);
+        }
+        if let Some(toggle) = &self.echo_cancel_toggle {
+            toggle.set_active(echo_cancelled);
+        }
+    }
+
+    /// Adds a button for removing a device the applet created.
+    pub fn add_remove_button(&mut self, on_remove: Rc<dyn Fn() + 'static>) {
+        let button =
+            gtk::Button::from_icon_name(Some("user-trash-symbolic"), gtk::IconSize::Button);
+        button.set_tooltip_text(Some("Remove"));
+        button.connect_clicked(move |_| {
+            on_remove();
+        });
+
+        self.grid.attach_next_to(
+            &button,
+            Some(&self.mute_button),
+            gtk::PositionType::Right,
+            3,
+            3,
+        );
+
+        self.remove_button = Some(button);
+    }
+
+    /// Adds a slider from -1 (left) to 1 (right) below the volume slider.
+    pub fn add_balance_slider(&mut self, balance: f32, on_change: Rc<dyn Fn(f32) + 'static>) {
+        let balance_slider = gtk::Scale::with_range(gtk::Orientation::Horizontal, -1.0, 1.0, 0.05);
+        balance_slider.set_draw_value(false);
+        balance_slider.add_mark(0.0, gtk::PositionType::Bottom, None);
+        balance_slider.set_value(balance as f64);
+        balance_slider.set_tooltip_text(Some("Balance"));
+        // Only user changes emit change-value, so updates from the server
+        // aren't sent back.
+        balance_slider.connect_change_value(move |_, _, d: f64| -> glib::signal::Inhibit {
+            on_change(d.clamp(-1.0, 1.0) as f32);
+            gtk::Inhibit(false)
+        });
+
+        self.grid.attach_next_to(
+            &balance_slider,
+            Some(&self.volume_slider),
+            gtk::PositionType::Bottom,
+            26,
+            1,
+        );
+
+        self.balance_slider = Some(balance_slider);
+    }
+
+    pub fn set_balance(&self, balance: f32) {
+        if let Some(balance_slider) = &self.balance_slider {
+            balance_slider.set_value(balance as f64);
+        }
+    }
+
+    /// Adds a dropdown of `sinks`, given as ids and names, for choosing
+    /// where a stream plays.
+    pub fn add_sink_chooser(
+        &mut self,
+        sinks: &[(String, String)],
+        current: Option<&str>,
+        on_change: Rc<dyn Fn(String) + 'static>,
+    ) {
+        let chooser = chooser(&with_all_sensitive(sinks), current, on_change);
+        self.main_container.add(&chooser);
+        self.sink_chooser = Some(chooser);
+    }
+
+    pub fn set_sink(&self, sink_id: Option<&str>) {
+        if let Some(chooser) = &self.sink_chooser {
+            chooser.set_active_id(sink_id);
+        }
+    }
+
+    /// Adds a dropdown of the card's `profiles`, given as names and
+    /// descriptions.
+    pub fn add_profile_chooser(
+        &mut self,
+        profiles: &[(String, String)],
+        active: Option<&str>,
+        on_change: Rc<dyn Fn(String) + 'static>,
+    ) {
+        let chooser = chooser(&with_all_sensitive(profiles), active, on_change);
+        chooser.set_tooltip_text(Some("Profile"));
+        self.main_container.add(&chooser);
+        self.profile_chooser = Some(chooser);
+    }
+
+    pub fn set_profile(&self, profile: Option<&str>) {
+        if let Some(chooser) = &self.profile_chooser {
+            chooser.set_active_id(profile);
+        }
+    }
+
+    /// Adds a dropdown of the device's ports. Unplugged ones are greyed out.
+    pub fn add_port_chooser(
+        &mut self,
+        ports: &[Port],
+        active: Option<&str>,
+        on_change: Rc<dyn Fn(String) + 'static>,
+    ) {
+        let options: Vec<(String, String, bool)> = ports
+            .iter()
+            .map(|port| {
+                let label = match port.available {
+                    Some(true) => format!("{} (plugged in)", port.description),
+                    Some(false) => format!("{} (unplugged)", port.description),
+                    None => port.description.clone(),
+                };
+                (port.name.clone(), label, port.available != Some(false))
+            })
+            .collect();
+
+        let chooser = chooser(&options, active, on_change);
+        chooser.set_tooltip_text(Some("Port"));
+        self.main_container.add(&chooser);
+        self.port_chooser = Some(chooser);
+    }
+
+    pub fn set_port(&self, port: Option<&str>) {
+        if let Some(chooser) = &self.port_chooser {
+            chooser.set_active_id(port);
+        }
+    }
+
+    /// Lets the row be dragged onto a row that accepts stream drops.
+    pub fn make_draggable(&self, stream_id: String) {
+        self.root.drag_source_set(
+            ModifierType::BUTTON1_MASK,
+            &[stream_drag_target()],
+            DragAction::MOVE,
+        );
+        self.root.connect_drag_data_get(move |_, _, data, _, _| {
+            data.set(&data.target(), 8, stream_id.as_bytes());
+        });
+    }
+
+    pub fn accept_stream_drops(&self, on_drop: Rc<dyn Fn(String) + 'static>) {
+        self.root
+            .drag_dest_set(DestDefaults::ALL, &[stream_drag_target()], DragAction::MOVE);
+        self.root
+            .connect_drag_data_received(move |_, _, _, _, data, _, _| {
+                if let Ok(stream_id) = String::from_utf8(data.data()) {
+                    on_drop(stream_id);
+                }
+            });
+    }
+
     pub fn set_volume_slider(&self, value: f32) {
-        self.volume_slider.set_value(value as f64);
+        self.volume_slider
+            .set_value(OPTIONS.volume_scale.to_slider(value) as f64);
         self.set_volume_label(value);
     }
 
@@ -119,6 +489,55 @@ impl VolumeSlider {
         self.set_grayed_out_slider(muted);
     }
 
+    /// A snapshot of what the row shows, for tests.
+    #[cfg(test)]
+    pub fn inspect(&self) -> RowState {
+        let chosen =
+            |chooser: &Option<gtk::ComboBox>| Some(chooser.as_ref()?.active_id()?.to_string());
+        let echo_cancelled = self
+            .echo_cancel_badge
+            .as_ref()
+            .zip(self.echo_cancel_toggle.as_ref())
+            .map(|(badge, toggle)| {
+                assert_eq!(badge.is_visible(), toggle.is_active());
+                badge.is_visible()
+            });
+
+        RowState {
+            state: (
+                OPTIONS
+                    .volume_scale
+                    .from_slider(self.volume_slider.value() as f32),
+                self.volume_slider.opacity() < 1.0,
+            ),
+            name: self.name_label.as_ref().map(|label| {
+                (
+                    label.text().to_string(),
+                    label.tooltip_text().map(|text| text.to_string()),
+                )
+            }),
+            caption: self.caption.as_ref().map(|label| label.text().to_string()),
+            peak: self
+                .peak_meter
+                .is_visible()
+                .then(|| self.peak_meter.value() as f32),
+            balance: self
+                .balance_slider
+                .as_ref()
+                .map(|balance_slider| balance_slider.value() as f32),
+            port: chosen(&self.port_chooser),
+            profile: chosen(&self.profile_chooser),
+            sink: chosen(&self.sink_chooser),
+            default_selected: self
+                .default_selector
+                .as_ref()
+                .map(|button| button.is_active()),
+            listening: self.listen_toggle.as_ref().map(|toggle| toggle.is_active()),
+            echo_cancelled,
+            removable: self.remove_button.is_some(),
+        }
+    }
+
     fn set_grayed_out_slider(&self, muted: bool) {
         let slider_opacity = if muted { 0.5 } else { 1.0 };
         self.volume_slider.set_opacity(slider_opacity);
@@ -126,14 +545,90 @@ impl VolumeSlider {
     }
 }
 
+/// What a row shows. Controls a row doesn't have are `None`.
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub struct RowState {
+    /// The volume and whether it's shown muted.
+    pub state: (f32, bool),
+    /// The name as shown, and the full name in its tooltip when cut off.
+    pub name: Option<(String, Option<String>)>,
+    pub caption: Option<String>,
+    pub peak: Option<f32>,
+    pub balance: Option<f32>,
+    pub port: Option<String>,
+    pub profile: Option<String>,
+    /// The key of the sink a stream plays on.
+    pub sink: Option<String>,
+    pub default_selected: Option<bool>,
+    pub listening: Option<bool>,
+    pub echo_cancelled: Option<bool>,
+    pub removable: bool,
+}
+
 pub trait Percentise {
     fn format_volume(&self) -> String;
 }
 
 impl Percentise for f32 {
     fn format_volume(&self) -> String {
-        format!("{}%", self.round())
+        OPTIONS.volume_scale.format(*self)
+    }
+}
+
+/// A dropdown of `options`, given as ids, labels and whether they can be
+/// chosen.
+fn chooser(
+    options: &[(String, String, bool)],
+    active: Option<&str>,
+    on_change: Rc<dyn Fn(String) + 'static>,
+) -> gtk::ComboBox {
+    let store = gtk::ListStore::new(&[
+        String::static_type(),
+        String::static_type(),
+        bool::static_type(),
+    ]);
+    for (id, label, sensitive) in options {
+        store.insert_with_values(
+            None,
+            &[(0, id), (1, &substring_name(label.clone())), (2, sensitive)],
+        );
     }
+
+    let chooser = gtk::ComboBox::with_model(&store);
+    chooser.set_id_column(0);
+
+    let cell = gtk::CellRendererText::new();
+    chooser.pack_start(&cell, true);
+    chooser.add_attribute(&cell, "text", 1);
+    chooser.add_attribute(&cell, "sensitive", 2);
+
+    chooser.set_active_id(active);
+    chooser.connect_changed(move |chooser| {
+        if let Some(id) = chooser.active_id() {
+            on_change(id.to_string());
+        }
+    });
+    chooser
+}
+
+fn with_all_sensitive(options: &[(String, String)]) -> Vec<(String, String, bool)> {
+    options
+        .iter()
+        .map(|(id, label)| (id.clone(), label.clone(), true))
+        .collect()
+}
+
+fn stream_drag_target() -> TargetEntry {
+    TargetEntry::new("volapplet/stream", TargetFlags::SAME_APP, 0)
+}
+
+/// Shows `text` in `label`, cut short if it's long, with the full text in a
+/// tooltip.
+fn set_truncated_text(label: &gtk::Label, text: &str) {
+    let shown = substring_name(text.to_string());
+    label.set_tooltip_text((shown != text).then_some(text));
+    label.set_text(&shown);
 }
 
 fn substring_name(name: String) -> String {
@@ -167,7 +662,7 @@ fn get_icon(type_: &VolumeType, icon_name: Option<String>) -> gtk::Image {
         VolumeType::Input => {
             gtk::Image::from_icon_name(Some("audio-input-microphone"), gtk::IconSize::LargeToolbar)
         }
-        VolumeType::Stream => icon_name
+        VolumeType::Stream | VolumeType::Recording => icon_name
             .bind(|name| {
                 gtk::IconTheme::default()
                     .bind(|theme| {
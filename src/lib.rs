@@ -1,6 +1,9 @@
-use std::sync::Mutex;
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
-use audio::get_audio;
 use audio::WrappedAudio;
 use exception::Exception;
 use gtk::Application;
@@ -9,6 +12,8 @@ mod audio;
 mod elements;
 mod exception;
 mod popout;
+#[cfg(test)]
+mod test_harness;
 mod tray_icon;
 pub mod options;
 
@@ -17,7 +22,10 @@ use once_cell::sync::Lazy;
 use popout::Popout;
 use tray_icon::TrayIcon;
 
-static AUDIO: Lazy<Mutex<WrappedAudio>> = Lazy::new(|| Mutex::new(get_audio()));
+#[cfg(not(test))]
+static AUDIO: Lazy<Mutex<WrappedAudio>> = Lazy::new(|| Mutex::new(audio::get_audio()));
+#[cfg(test)]
+static AUDIO: Lazy<Mutex<WrappedAudio>> = Lazy::new(|| Mutex::new(audio::mock::wrapped()));
 
 pub fn run() {
     if gtk::init().is_err() {
@@ -36,5 +44,45 @@ pub fn run() {
         TrayIcon::initialise();
     });
 
+    // Quit on Ctrl+C or SIGTERM too, so the cleanup below runs.
+    let signalled = Rc::new(Cell::new(false));
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let app = app.clone();
+        let signalled = signalled.clone();
+        gtk::glib::unix_signal_add_local(signal, move || {
+            signalled.set(true);
+            app.quit();
+            gtk::glib::Continue(false)
+        });
+    }
+
     app.run_with_args(&["vol-applet"]);
+
+    // Nobody may be there to answer when stopped by a signal, e.g. on
+    // logout, so the devices are kept.
+    let created = AUDIO.lock().unwrap().aud.created_devices();
+    if !created.is_empty() && !signalled.get() && confirm_removing_created_devices(&created) {
+        AUDIO.lock().unwrap().aud.remove_created_devices();
+    }
+
+    // Lets backends undo changes they made to the sound server.
+    if let Some(aud) = Arc::get_mut(&mut AUDIO.lock().unwrap().aud) {
+        aud.cleanup();
+    }
+}
+
+/// Asks whether the virtual devices the applet made should go with it.
+fn confirm_removing_created_devices(devices: &[String]) -> bool {
+    let dialog = gtk::MessageDialog::new(
+        None::<&gtk::Window>,
+        gtk::DialogFlags::MODAL,
+        gtk::MessageType::Question,
+        gtk::ButtonsType::YesNo,
+        "Remove the virtual devices volapplet created?",
+    );
+    dialog.set_secondary_text(Some(&devices.join(", ")));
+
+    let response = dialog.run();
+    dialog.close();
+    response == gtk::ResponseType::Yes
 }
@@ -2,12 +2,21 @@ use std::{sync::Arc, env};
 
 use once_cell::sync::Lazy;
 
-use crate::exception::Exception;
+use crate::{
+    audio::{volume_from_db, volume_to_db},
+    exception::Exception,
+};
 
 pub static OPTIONS: Lazy<Arc<Options>> = Lazy::new(|| {
     let args: Vec<String> = env::args().skip(1).collect();
     let options = match Options::from_args(args) {
-        Ok(options) => options,
+        Ok(mut options) => {
+            // Like other PulseAudio clients, the flag wins over the variable.
+            if options.server.is_none() {
+                options.server = env::var("PULSE_SERVER").ok().filter(|s| !s.is_empty());
+            }
+            options
+        }
         Err(e) => {
             e.log_and_exit();
             unreachable!();
@@ -20,22 +29,147 @@ pub static OPTIONS: Lazy<Arc<Options>> = Lazy::new(|| {
 pub struct Options {
     pub show_inputs: bool,
     pub show_streams: bool,
+    /// Show the monitor source of each sink.
+    pub show_monitors: bool,
     pub show_icons: bool,
     pub dont_group: bool,
+    /// `None` means the backend is picked automatically.
+    pub backend: Option<Backend>,
+    /// Highest volume in percent. Above 100 over-amplifies.
+    pub max_volume: f32,
+    pub volume_scale: VolumeScale,
+    /// PulseAudio server to connect to instead of the default one.
+    pub server: Option<String>,
+    /// Latency in milliseconds of playing an input back to hear it.
+    pub monitor_latency: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Backend {
+    Pulse,
+    Pipewire,
+    Jack,
+    Alsa,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Pulse => "PulseAudio",
+            Backend::Pipewire => "PipeWire",
+            Backend::Jack => "JACK",
+            Backend::Alsa => "ALSA",
+        }
+    }
+}
+
+/// How volumes are shown. Backends always report the cubic scale, where
+/// 100 is the device's normal volume; on every scale that stays at 100 on
+/// the slider.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeScale {
+    /// Amplitude, as a percentage.
+    Linear,
+    /// The perceptual scale pavucontrol uses.
+    Cubic,
+    /// Decibels, with the slider covering `DB_RANGE` below 0 dB.
+    Decibel,
+}
+
+/// How far below 0 dB the slider reaches on the dB scale.
+const DB_RANGE: f32 = 60.;
+
+impl VolumeScale {
+    pub fn to_slider(&self, volume: f32) -> f32 {
+        match self {
+            VolumeScale::Linear => (volume / 100.).powi(3) * 100.,
+            VolumeScale::Cubic => volume,
+            VolumeScale::Decibel => (100. * (1. + volume_to_db(volume) / DB_RANGE)).max(0.),
+        }
+    }
+
+    pub fn from_slider(&self, position: f32) -> f32 {
+        match self {
+            VolumeScale::Linear => (position.max(0.) / 100.).cbrt() * 100.,
+            VolumeScale::Cubic => position,
+            VolumeScale::Decibel if position <= 0. => 0.,
+            VolumeScale::Decibel => volume_from_db((position / 100. - 1.) * DB_RANGE),
+        }
+    }
+
+    pub fn format(&self, volume: f32) -> String {
+        match self {
+            VolumeScale::Decibel if volume <= 0. => "-∞ dB".to_string(),
+            VolumeScale::Decibel => format!("{:.1} dB", volume_to_db(volume)),
+            _ => format!("{}%", self.to_slider(volume).round()),
+        }
+    }
+}
+
+fn parse_volume_scale(name: &str) -> Result<VolumeScale, Exception> {
+    match name {
+        "linear" => Ok(VolumeScale::Linear),
+        "cubic" => Ok(VolumeScale::Cubic),
+        "db" => Ok(VolumeScale::Decibel),
+        _ => Err(Exception::Misc(format!("Unknown volume scale: {}", name))),
+    }
+}
+
+fn parse_backend(name: &str) -> Result<Option<Backend>, Exception> {
+    match name {
+        "auto" => Ok(None),
+        "pulse" => Ok(Some(Backend::Pulse)),
+        "pipewire" => Ok(Some(Backend::Pipewire)),
+        "jack" => Ok(Some(Backend::Jack)),
+        "alsa" => Ok(Some(Backend::Alsa)),
+        _ => Err(Exception::Misc(format!("Unknown backend: {}", name))),
+    }
+}
+
+fn parse_max_volume(value: &str) -> Result<f32, Exception> {
+    match value.parse::<f32>() {
+        Ok(max) if max >= 100. && max.is_finite() => Ok(max),
+        _ => Err(Exception::Misc(format!(
+            "Maximum volume must be a number of at least 100: {}",
+            value
+        ))),
+    }
+}
+
+fn parse_latency(value: &str) -> Result<u32, Exception> {
+    match value.parse::<u32>() {
+        Ok(latency) if latency > 0 => Ok(latency),
+        _ => Err(Exception::Misc(format!(
+            "Latency must be a whole number of milliseconds: {}",
+            value
+        ))),
+    }
 }
 
 impl Options {
     pub fn from_args(args: Vec<String>) -> Result<Options, Exception> {
         let mut options = Options::default();
 
-        let args = split_small_flags(args);
+        let mut args = split_small_flags(split_flag_values(args)).into_iter();
 
-        for arg in args.iter() {
+        while let Some(arg) = args.next() {
             match arg.as_str() {
                 "-i" | "--show-inputs" => options.show_inputs = true,
                 "-s" | "--show-streams" => options.show_streams = true,
+                "-m" | "--show-monitors" => options.show_monitors = true,
                 "-d" | "--dont-group" => options.dont_group = true,
                 "-c" | "--show-icons" => options.show_icons = true,
+                "--backend" => options.backend = parse_backend(&take_value(&arg, &mut args)?)?,
+                "--volume-scale" => {
+                    options.volume_scale = parse_volume_scale(&take_value(&arg, &mut args)?)?
+                }
+                "--max-volume" => {
+                    options.max_volume = parse_max_volume(&take_value(&arg, &mut args)?)?
+                }
+                "--server" => options.server = Some(take_value(&arg, &mut args)?),
+                "--monitor-latency" => {
+                    options.monitor_latency = parse_latency(&take_value(&arg, &mut args)?)?
+                }
                 "-h" | "--help" => {
                     help();
                     unreachable!();
@@ -50,6 +184,23 @@ impl Options {
     }
 }
 
+fn take_value(flag: &str, args: &mut impl Iterator<Item = String>) -> Result<String, Exception> {
+    args.next()
+        .ok_or_else(|| Exception::Misc(format!("Missing value for option: {}", flag)))
+}
+
+/// Splits `--flag=value` into `--flag` and `value`.
+fn split_flag_values(args: Vec<String>) -> Vec<String> {
+    args.into_iter()
+        .flat_map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                vec![flag.to_string(), value.to_string()]
+            }
+            _ => vec![arg],
+        })
+        .collect()
+}
+
 fn split_small_flags(args: Vec<String>) -> Vec<String> {
     args.into_iter()
         .map(|arg| {
@@ -79,11 +230,17 @@ fn help() {
     println!("Usage: volapplet [options]");
     println!();
     println!("Options:");
-    println!("  -i, --show-inputs       Show input devices.");
-    println!("  -s, --show-streams      Show streams.");
-    println!("  -c, --show-icons        Show icons.");
-    println!("  -d, --dont-group        Don't group streams and inputs into expandable tabs.");
-    println!("  -h, --help              Show this help message and exit.");
+    println!("  -i, --show-inputs           Show input devices.");
+    println!("  -s, --show-streams          Show streams.");
+    println!("  -m, --show-monitors         Show monitors of output devices.");
+    println!("  -c, --show-icons            Show icons.");
+    println!("  -d, --dont-group            Don't group streams and inputs into expandable tabs.");
+    println!("      --backend <name>        Audio backend: pulse, pipewire, jack, alsa or auto.");
+    println!("      --max-volume <n>        Highest volume in percent. Defaults to 100.");
+    println!("      --volume-scale <s>      Show volumes as linear, cubic or db. Defaults to cubic.");
+    println!("      --server <address>      PulseAudio server to connect to. Defaults to $PULSE_SERVER.");
+    println!("      --monitor-latency <ms>  Delay when monitoring an input. Defaults to 50.");
+    println!("  -h, --help                  Show this help message and exit.");
 
     std::process::exit(0);
 }
@@ -93,8 +250,14 @@ impl Default for Options {
         Options {
             show_inputs: false,
             show_streams: false,
+            show_monitors: false,
             show_icons: false,
             dont_group: false,
+            backend: None,
+            max_volume: 100.,
+            volume_scale: VolumeScale::Cubic,
+            server: None,
+            monitor_latency: 50,
         }
     }
 }
@@ -120,8 +283,128 @@ mod tests {
         assert!(options.show_streams);
         assert!(!options.show_icons);
         assert!(options.dont_group);
+        assert!(!options.show_monitors);
+
+        let args = vec!["-im".to_string()];
+        let options = Options::from_args(args).unwrap();
+
+        assert!(options.show_inputs);
+        assert!(options.show_monitors);
 
         let args = vec!["a".to_string()];
         assert!(Options::from_args(args).is_err());
     }
+
+    #[test]
+    fn backend() {
+        let options = Options::from_args(vec![]).unwrap();
+        assert_eq!(options.backend, None);
+
+        let args = vec!["--backend".to_string(), "pipewire".to_string()];
+        let options = Options::from_args(args).unwrap();
+        assert_eq!(options.backend, Some(Backend::Pipewire));
+
+        let args = vec!["-i".to_string(), "--backend=pulse".to_string()];
+        let options = Options::from_args(args).unwrap();
+        assert!(options.show_inputs);
+        assert_eq!(options.backend, Some(Backend::Pulse));
+
+        let args = vec!["--backend=jack".to_string()];
+        assert_eq!(
+            Options::from_args(args).unwrap().backend,
+            Some(Backend::Jack)
+        );
+
+        let args = vec!["--backend=auto".to_string()];
+        assert_eq!(Options::from_args(args).unwrap().backend, None);
+
+        let args = vec!["--backend".to_string()];
+        assert!(Options::from_args(args).is_err());
+
+        let args = vec!["--backend".to_string(), "oss".to_string()];
+        assert!(Options::from_args(args).is_err());
+    }
+
+    #[test]
+    fn max_volume() {
+        let options = Options::from_args(vec![]).unwrap();
+        assert_eq!(options.max_volume, 100.);
+
+        let args = vec!["--max-volume=150".to_string()];
+        assert_eq!(Options::from_args(args).unwrap().max_volume, 150.);
+
+        let args = vec!["--max-volume".to_string(), "50".to_string()];
+        assert!(Options::from_args(args).is_err());
+
+        let args = vec!["--max-volume".to_string(), "loud".to_string()];
+        assert!(Options::from_args(args).is_err());
+    }
+
+    #[test]
+    fn server() {
+        let options = Options::from_args(vec![]).unwrap();
+        assert_eq!(options.server, None);
+
+        let args = vec!["--server=tcp:workstation".to_string()];
+        assert_eq!(
+            Options::from_args(args).unwrap().server.as_deref(),
+            Some("tcp:workstation")
+        );
+
+        let args = vec!["--server".to_string(), "unix:/run/pa/native".to_string()];
+        assert_eq!(
+            Options::from_args(args).unwrap().server.as_deref(),
+            Some("unix:/run/pa/native")
+        );
+
+        let args = vec!["--server".to_string()];
+        assert!(Options::from_args(args).is_err());
+    }
+
+    #[test]
+    fn monitor_latency() {
+        let options = Options::from_args(vec![]).unwrap();
+        assert_eq!(options.monitor_latency, 50);
+
+        let args = vec!["--monitor-latency=20".to_string()];
+        assert_eq!(Options::from_args(args).unwrap().monitor_latency, 20);
+
+        let args = vec!["--monitor-latency".to_string(), "0".to_string()];
+        assert!(Options::from_args(args).is_err());
+
+        let args = vec!["--monitor-latency".to_string(), "1.5".to_string()];
+        assert!(Options::from_args(args).is_err());
+    }
+
+    #[test]
+    fn volume_scales() {
+        let args = vec!["--volume-scale=db".to_string()];
+        assert_eq!(
+            Options::from_args(args).unwrap().volume_scale,
+            VolumeScale::Decibel
+        );
+
+        let args = vec!["--volume-scale".to_string(), "log".to_string()];
+        assert!(Options::from_args(args).is_err());
+
+        for scale in [
+            VolumeScale::Linear,
+            VolumeScale::Cubic,
+            VolumeScale::Decibel,
+        ] {
+            assert!((scale.to_slider(100.) - 100.).abs() < 0.001);
+            assert_eq!(scale.to_slider(0.), 0.);
+            assert_eq!(scale.from_slider(0.), 0.);
+            assert!((scale.from_slider(scale.to_slider(50.)) - 50.).abs() < 0.001);
+        }
+
+        assert!((VolumeScale::Linear.to_slider(50.) - 12.5).abs() < 0.001);
+        assert!((VolumeScale::Decibel.to_slider(10.) - 0.).abs() < 0.001);
+        assert_eq!(VolumeScale::Decibel.from_slider(-5.), 0.);
+
+        assert_eq!(VolumeScale::Cubic.format(49.6), "50%");
+        assert_eq!(VolumeScale::Linear.format(50.), "13%");
+        assert_eq!(VolumeScale::Decibel.format(50.), "-18.1 dB");
+        assert_eq!(VolumeScale::Decibel.format(0.), "-∞ dB");
+    }
 }
@@ -4,11 +4,16 @@ use std::sync::Mutex;
 
 use gtk::gdk::{EventKey, SeatCapabilities};
 use gtk::glib::idle_add_once;
-use gtk::traits::{ContainerExt, ExpanderExt, GtkWindowExt, WidgetExt};
+use gtk::traits::{
+    ButtonExt, ContainerExt, EntryExt, ExpanderExt, GtkWindowExt, StyleContextExt, ToggleButtonExt,
+    WidgetExt,
+};
 use gtk::{Application, ApplicationWindow, Inhibit};
 
 use crate::audio::reload_outputs_in_popout;
-use crate::audio::shared_output_list::{self, VolumeType};
+use crate::audio::shared_output_list::{self, OutputId, SourceType, VolumeType};
+#[cfg(test)]
+use crate::elements::RowState;
 use crate::elements::VolumeSlider;
 use crate::options::OPTIONS;
 use crate::tray_icon::TrayIcon;
@@ -19,8 +24,17 @@ static POPOUT: Mutex<Option<Popout>> = Mutex::new(None);
 pub struct Popout {
     pub container: gtk::Box,
     pub popout_menu: ApplicationWindow,
-    pub sliders: HashMap<String, Box<VolumeSlider>>,
-    ignore_next_callback: bool,
+    pub sliders: HashMap<OutputId, Box<VolumeSlider>>,
+    /// Where new stream and recording rows go, if they have a place.
+    streams_container: Option<gtk::Box>,
+    recordings_container: Option<gtk::Box>,
+    server_label: Option<gtk::Label>,
+    /// Whether to offer making virtual sinks.
+    can_create_virtual_sinks: bool,
+    can_listen_to_inputs: bool,
+    can_cancel_echo: bool,
+    /// The output whose change the backend is about to echo back.
+    ignore_next_callback: Option<OutputId>,
 }
 unsafe impl Sync for Popout {}
 unsafe impl Send for Popout {}
@@ -78,22 +92,49 @@ impl Popout {
             container,
             popout_menu: win,
             sliders: HashMap::new(),
-            ignore_next_callback: false,
+            streams_container: None,
+            recordings_container: None,
+            server_label: None,
+            can_create_virtual_sinks: AUDIO.lock().unwrap().aud.can_create_virtual_sinks(),
+            can_listen_to_inputs: AUDIO.lock().unwrap().aud.can_listen_to_inputs(),
+            can_cancel_echo: AUDIO.lock().unwrap().aud.can_cancel_echo(),
+            ignore_next_callback: None,
         };
 
         POPOUT.lock().unwrap().replace(popout);
     }
 
-    pub fn handle_callback(f: fn(&mut Popout)) {
+    pub fn handle_callback(f: impl FnOnce(&mut Popout)) {
         let mut a = POPOUT.lock().unwrap();
-        let popout = a.as_mut().unwrap();
-        if popout.ignore_next_callback {
-            popout.ignore_next_callback = false;
+        let popout = match a.as_mut() {
+            Some(popout) => popout,
+            // Backends can emit events before the UI is built.
+            None => return,
+        };
+        if popout.ignore_next_callback.take().is_some() {
             return;
         }
         f(popout);
     }
 
+    /// Like `handle_callback`, for backends that report objects one at a
+    /// time. Only the echo of a change to the same object is ignored, as
+    /// nothing refetches other objects later. Returns whether to ignore the
+    /// event; the popout isn't locked while the caller handles it, as that
+    /// takes the output list.
+    pub fn ignores_object_event(is_echo: impl FnOnce(&OutputId) -> bool) -> bool {
+        let mut a = POPOUT.lock().unwrap();
+        let popout = match a.as_mut() {
+            Some(popout) => popout,
+            None => return true,
+        };
+        if popout.ignore_next_callback.as_ref().is_some_and(is_echo) {
+            popout.ignore_next_callback = None;
+            return true;
+        }
+        false
+    }
+
     fn set_geomerty(&mut self) {
         self.popout_menu.set_size_request(320, 50);
         let (window_x, window_y) = self.popout_menu.position();
@@ -137,13 +178,47 @@ impl Popout {
         self.popout_menu.move_(x, y);
     }
 
-    pub fn set_ignore_next_callback() {
+    /// Finds a row by output name, or by id for unnamed outputs.
+    #[cfg(test)]
+    fn slider(&self, output_id: &str) -> Option<&VolumeSlider> {
+        self.sliders
+            .iter()
+            .find(|(id, _)| id.name.as_deref() == Some(output_id) || id.to_string() == output_id)
+            .map(|(_, slider)| slider.as_ref())
+    }
+
+    /// What an output's row shows, with its sink by name.
+    #[cfg(test)]
+    pub fn row(output_id: &str) -> Option<RowState> {
+        let a = POPOUT.lock().unwrap();
+        let mut row = a.as_ref()?.slider(output_id)?.inspect();
+        row.sink = row
+            .sink
+            .and_then(|key| shared_output_list::find_by_key(&key)?.name);
+        Some(row)
+    }
+
+    #[cfg(test)]
+    pub fn server_info() -> Option<String> {
+        let a = POPOUT.lock().unwrap();
+        let label = a.as_ref()?.server_label.as_ref()?;
+        Some(gtk::traits::LabelExt::text(label).to_string())
+    }
+
+    pub fn set_ignore_next_callback(output_id: OutputId) {
         let mut a = POPOUT.lock().unwrap();
         let popout = a.as_mut().unwrap();
-        popout.ignore_next_callback = true;
+        popout.ignore_next_callback = Some(output_id);
     }
 
-    pub fn set_specific_volume(output_id: String, volume: f32) {
+    /// Drops the flag when no echo is coming, e.g. for a rejected change.
+    pub fn clear_ignore_next_callback() {
+        if let Some(popout) = POPOUT.lock().unwrap().as_mut() {
+            popout.ignore_next_callback = None;
+        }
+    }
+
+    pub fn set_specific_volume(output_id: OutputId, volume: f32) {
         idle_add_once(move || {
             let mut a = POPOUT.lock().unwrap();
             let popout = a.as_mut().unwrap();
@@ -153,7 +228,7 @@ impl Popout {
         });
     }
 
-    pub fn set_specific_volume_label(output_id: String, volume: f32) {
+    pub fn set_specific_volume_label(output_id: OutputId, volume: f32) {
         idle_add_once(move || {
             let mut a = POPOUT.lock().unwrap();
             let popout = a.as_mut().unwrap();
@@ -163,7 +238,7 @@ impl Popout {
         });
     }
 
-    pub fn set_specific_muted(output_id: String, muted: bool) {
+    pub fn set_specific_muted(output_id: OutputId, muted: bool) {
         idle_add_once(move || {
             let mut a = POPOUT.lock().unwrap();
             let popout = a.as_mut().unwrap();
@@ -173,6 +248,149 @@ impl Popout {
         });
     }
 
+    /// The line under an output's name.
+    pub fn caption_for(output: &audio::shared_output_list::Output) -> Option<String> {
+        if !output.members.is_empty() {
+            Some(format!("Plays on {}", output.members.join(", ")))
+        } else if let Some(source_type) = &output.source_type {
+            Some(source_type.label().to_string())
+        } else {
+            output.detail.clone()
+        }
+    }
+
+    pub fn set_specific_label(output_id: OutputId, name: String, caption: Option<String>) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get_mut(&output_id) {
+                output.set_label(&name, caption.as_deref());
+            }
+        });
+    }
+
+    pub fn set_specific_listening(output_id: OutputId, listening: bool) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_listening(listening);
+            }
+        });
+    }
+
+    pub fn set_specific_echo_cancelled(output_id: OutputId, echo_cancelled: bool) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_echo_cancelled(echo_cancelled);
+            }
+        });
+    }
+
+    pub fn set_specific_peak(output_id: OutputId, peak: f32) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_peak(peak);
+            }
+        });
+    }
+
+    pub fn set_specific_balance(output_id: OutputId, balance: f32) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_balance(balance);
+            }
+        });
+    }
+
+    pub fn set_specific_port(output_id: OutputId, port: Option<String>) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_port(port.as_deref());
+            }
+        });
+    }
+
+    pub fn set_specific_profile(output_id: OutputId, profile: Option<String>) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_profile(profile.as_deref());
+            }
+        });
+    }
+
+    pub fn set_specific_sink(output_id: OutputId, sink_id: Option<OutputId>) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(output) = popout.sliders.get(&output_id) {
+                output.set_sink(sink_id.map(|sink_id| sink_id.to_string()).as_deref());
+            }
+        });
+    }
+
+    /// Adds a row for a stream or recording without rebuilding the others.
+    pub fn add_output(output: shared_output_list::Output) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+
+            let container = match output.id.type_ {
+                VolumeType::Stream => popout.streams_container.clone(),
+                VolumeType::Recording => popout.recordings_container.clone(),
+                _ => None,
+            };
+            let container = match container {
+                Some(container) if !popout.sliders.contains_key(&output.id) => container,
+                _ => {
+                    drop(a);
+                    Popout::update_outputs();
+                    return;
+                }
+            };
+
+            let mut context = RowContext::new(&shared_output_list::get_output_list());
+            let id = output.id.clone();
+            let slider = popout.append_volume_slider(&container, output, &mut context);
+            popout.sliders.insert(id, Box::new(slider));
+            container.show_all();
+        });
+    }
+
+    pub fn remove_output(output_id: OutputId) {
+        idle_add_once(move || {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+            if let Some(slider) = popout.sliders.remove(&output_id) {
+                slider.remove();
+            }
+        });
+    }
+
+    pub fn update_default_selectors() {
+        idle_add_once(|| {
+            let outputs = shared_output_list::get_output_list();
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+
+            for output in outputs.iter().filter(|output| output.is_default_device()) {
+                if let Some(slider) = popout.sliders.get(&output.id) {
+                    slider.set_default_selected();
+                }
+            }
+        });
+    }
+
     pub fn update_outputs() {
         idle_add_once(|| {
             let mut a = POPOUT.lock().unwrap();
@@ -192,28 +410,163 @@ impl Popout {
         }
     }
 
+    /// Replaces the rows with a placeholder while the backend reconnects.
+    pub fn show_reconnecting() {
+        idle_add_once(|| {
+            let mut a = POPOUT.lock().unwrap();
+            let popout = a.as_mut().unwrap();
+
+            remove_child_widgets(popout);
+            popout.sliders = HashMap::new();
+            popout.streams_container = None;
+            popout.recordings_container = None;
+            popout.server_label = None;
+
+            popout
+                .container
+                .add(&gtk::Label::builder().label("Reconnecting…").build());
+            popout.container.show_all();
+        });
+    }
+
     fn append_volume_slider(
         &self,
         container: &gtk::Box,
         output: audio::shared_output_list::Output,
-        is_default: bool,
+        context: &mut RowContext,
     ) -> VolumeSlider {
         let id = output.id.clone();
         let id_ = output.id.clone();
-        VolumeSlider::new(
+        let is_default = output.is_default_device();
+        let type_ = output.id.type_.clone();
+        let caption = Popout::caption_for(&output);
+
+        let mut slider = VolumeSlider::new(
             container,
             Some(output.name),
-            output.type_,
+            output.id.type_.clone(),
             output.icon_name,
             output.volume,
             output.muted,
             Rc::new(move |vol: f32| {
-                handle_volume_slider_change(is_default, vol, id.clone());
+                handle_volume_slider_change(vol, id.clone());
             }),
             Rc::new(move || {
                 handle_mute_button(id_.clone());
             }),
-        )
+        );
+
+        if let Some(caption) = caption {
+            slider.add_caption(&caption);
+        }
+
+        if shared_output_list::has_balance(&output.channels) {
+            let id = output.id.clone();
+            slider.add_balance_slider(
+                shared_output_list::get_balance(&output.channels),
+                Rc::new(move |balance: f32| {
+                    handle_balance_change(balance, id.clone());
+                }),
+            );
+        }
+
+        let group = match type_ {
+            VolumeType::Sink => &mut context.sink_group,
+            VolumeType::Input => &mut context.input_group,
+            VolumeType::Stream => {
+                let id = output.id.clone();
+                let sink_key = output.sink_id.as_ref().map(|sink_id| sink_id.to_string());
+                slider.add_sink_chooser(
+                    &context.sinks,
+                    sink_key.as_deref(),
+                    Rc::new(move |sink_key| {
+                        if let Some(sink_id) = shared_output_list::find_by_key(&sink_key) {
+                            handle_stream_moved(id.clone(), sink_id);
+                        }
+                    }),
+                );
+                slider.make_draggable(output.id.to_string());
+                return slider;
+            }
+            VolumeType::Recording => return slider,
+        };
+
+        if output.ports.len() > 1 {
+            let id = output.id.clone();
+            slider.add_port_chooser(
+                &output.ports,
+                output.active_port.as_deref(),
+                Rc::new(move |port| {
+                    handle_port_selected(id.clone(), port);
+                }),
+            );
+        }
+
+        // Playing a sink's monitor back would feed it into itself.
+        if self.can_listen_to_inputs
+            && matches!(type_, VolumeType::Input)
+            && output.source_type != Some(SourceType::Monitor)
+        {
+            let id = output.id.clone();
+            slider.add_listen_toggle(
+                output.listening,
+                Rc::new(move |listening| {
+                    handle_listen_toggled(id.clone(), listening);
+                }),
+            );
+        }
+
+        if self.can_cancel_echo
+            && matches!(type_, VolumeType::Input)
+            && output.source_type != Some(SourceType::Monitor)
+        {
+            let id = output.id.clone();
+            slider.add_echo_cancel_toggle(
+                output.echo_cancelled,
+                Rc::new(move |echo_cancelled| {
+                    handle_echo_cancel_toggled(id.clone(), echo_cancelled);
+                }),
+            );
+        }
+
+        if output.removable {
+            let id = output.id.clone();
+            slider.add_remove_button(Rc::new(move || {
+                handle_remove_virtual_device(id.clone());
+            }));
+        }
+
+        if let Some(card) = output.card.filter(|card| card.profiles.len() > 1) {
+            let card_id = card.id.clone();
+            slider.add_profile_chooser(
+                &card.profiles,
+                card.active_profile.as_deref(),
+                Rc::new(move |profile| {
+                    handle_profile_selected(card_id.clone(), profile);
+                }),
+            );
+        }
+
+        if let VolumeType::Sink = type_ {
+            let id = output.id.clone();
+            slider.accept_stream_drops(Rc::new(move |stream_key| {
+                if let Some(stream_id) = shared_output_list::find_by_key(&stream_key) {
+                    handle_stream_moved(stream_id, id.clone());
+                }
+            }));
+        }
+
+        let id = output.id;
+        let button = slider.add_default_selector(
+            group.as_ref(),
+            is_default,
+            Rc::new(move || {
+                handle_default_selected(id.clone());
+            }),
+        );
+        group.get_or_insert(button);
+
+        slider
     }
 
     pub fn show() {
@@ -229,6 +582,10 @@ impl Popout {
         popout.popout_menu.show();
         // popout.popout_menu.present();
         popout.set_geomerty();
+        drop(a);
+
+        // Meters only run while they can be seen.
+        AUDIO.lock().unwrap().aud.start_peak_meters();
     }
 
     pub fn hide() {
@@ -236,32 +593,87 @@ impl Popout {
         let popout = a.as_mut().unwrap();
         popout.popout_menu.hide();
         // ungrab(&popout.popout_menu.window().unwrap());
+        drop(a);
+
+        AUDIO.lock().unwrap().aud.stop_peak_meters();
+    }
+}
+
+/// What rows need to know about each other while the popout is built.
+struct RowContext {
+    /// Keys and names of the sinks streams can be moved to.
+    sinks: Vec<(String, String)>,
+    /// The radio groups of the default selectors.
+    sink_group: Option<gtk::RadioButton>,
+    input_group: Option<gtk::RadioButton>,
+}
+
+impl RowContext {
+    fn new(outputs: &[shared_output_list::Output]) -> RowContext {
+        RowContext {
+            sinks: outputs
+                .iter()
+                .filter(|output| matches!(output.id.type_, VolumeType::Sink))
+                .map(|output| (output.id.to_string(), output.name.clone()))
+                .collect(),
+            sink_group: None,
+            input_group: None,
+        }
     }
 }
 
 fn add_outputs_from_list(popout: &mut Popout, container: gtk::Box) {
     let outputs = audio::shared_output_list::get_output_list();
+    let combinable: Vec<(OutputId, String)> = outputs
+        .iter()
+        .filter(|output| matches!(output.id.type_, VolumeType::Sink) && output.members.is_empty())
+        .map(|output| (output.id.clone(), output.name.clone()))
+        .collect();
     popout.sliders = HashMap::new();
+    popout.streams_container = None;
+    popout.recordings_container = None;
+
+    // At the top, so rows added later don't end up below it.
+    popout.server_label = shared_output_list::get_server_info().map(|info| {
+        let label = gtk::Label::builder()
+            .label(&info)
+            .halign(gtk::Align::Start)
+            .margin_bottom(5)
+            .build();
+        label.style_context().add_class("dim-label");
+        popout.container.add(&label);
+        label
+    });
 
     if outputs.is_empty() {
         popout
             .container
             .add(&gtk::Label::builder().label("No devices found.").build());
-        return;
-    }
-
-    if OPTIONS.dont_group {
+    } else if OPTIONS.dont_group {
+        // Rows go in their own box, so ones added later stay above the
+        // actions.
+        let rows = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        container.add(&rows);
+        popout.streams_container = Some(rows.clone());
+        popout.recordings_container = Some(rows.clone());
+        let mut context = RowContext::new(&outputs);
         for output in outputs {
-            let is_default = output.is_default();
             popout.sliders.insert(
                 output.id.clone(),
-                Box::new(popout.append_volume_slider(&container, output, is_default)),
+                Box::new(popout.append_volume_slider(&rows, output, &mut context)),
             );
         }
     } else {
         create_grouped(outputs, popout, container);
     }
 
+    if popout.can_create_virtual_sinks {
+        popout.container.add(&new_virtual_device_action());
+        if combinable.len() > 1 {
+            popout.container.add(&combine_outputs_action(combinable));
+        }
+    }
+
     reposition_once_resized();
 }
 
@@ -288,6 +700,24 @@ fn create_grouped(
 
     streams.add(&streams_container);
 
+    let recordings = gtk::Expander::builder().label("Recording").build();
+
+    let recordings_container = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .margin_top(10)
+        .build();
+
+    recordings.add(&recordings_container);
+
+    let monitors = gtk::Expander::builder().label("Monitors").build();
+
+    let monitors_container = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .margin_top(10)
+        .build();
+
+    monitors.add(&monitors_container);
+
     inputs.connect_expanded_notify(|_| {
         reposition_once_resized();
     });
@@ -296,16 +726,33 @@ fn create_grouped(
         reposition_once_resized();
     });
 
+    recordings.connect_expanded_notify(|_| {
+        reposition_once_resized();
+    });
+
+    monitors.connect_expanded_notify(|_| {
+        reposition_once_resized();
+    });
+
+    let mut context = RowContext::new(&outputs);
+
     for output in outputs {
         let id = output.id.clone();
 
-        let slider = Box::new(match output.type_ {
-            VolumeType::Sink => {
-                let is_default = output.is_default();
-                popout.append_volume_slider(&container, output, is_default)
+        let slider = Box::new(match output.id.type_ {
+            VolumeType::Sink => popout.append_volume_slider(&container, output, &mut context),
+            VolumeType::Stream => {
+                popout.append_volume_slider(&streams_container, output, &mut context)
+            }
+            VolumeType::Input if output.source_type == Some(SourceType::Monitor) => {
+                popout.append_volume_slider(&monitors_container, output, &mut context)
+            }
+            VolumeType::Input => {
+                popout.append_volume_slider(&inputs_container, output, &mut context)
+            }
+            VolumeType::Recording => {
+                popout.append_volume_slider(&recordings_container, output, &mut context)
             }
-            VolumeType::Stream => popout.append_volume_slider(&streams_container, output, false),
-            VolumeType::Input => popout.append_volume_slider(&inputs_container, output, false),
         });
 
         popout.sliders.insert(id, slider);
@@ -317,7 +764,105 @@ fn create_grouped(
 
     if OPTIONS.show_streams {
         popout.container.add(&streams);
+        popout.streams_container = Some(streams_container);
+    }
+
+    if OPTIONS.show_inputs {
+        popout.container.add(&recordings);
+        popout.recordings_container = Some(recordings_container);
     }
+
+    if OPTIONS.show_monitors {
+        popout.container.add(&monitors);
+    }
+}
+
+/// A button that turns into a name entry for creating a virtual sink.
+fn new_virtual_device_action() -> gtk::Box {
+    let action = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    action.set_margin_top(10);
+
+    let button = gtk::Button::with_label("New virtual device…");
+    let entry = gtk::Entry::builder()
+        .placeholder_text("Name")
+        .hexpand(true)
+        .build();
+    let create = gtk::Button::with_label("Create");
+    entry.set_no_show_all(true);
+    create.set_no_show_all(true);
+
+    let (entry_, create_) = (entry.clone(), create.clone());
+    button.connect_clicked(move |button| {
+        button.hide();
+        entry_.show();
+        create_.show();
+        entry_.grab_focus();
+    });
+
+    let entry_ = entry.clone();
+    create.connect_clicked(move |_| {
+        handle_new_virtual_device(entry_.text().to_string());
+    });
+    entry.connect_activate(|entry| {
+        handle_new_virtual_device(entry.text().to_string());
+    });
+
+    action.add(&button);
+    action.add(&entry);
+    action.add(&create);
+    action
+}
+
+/// A button that turns into a checklist of `sinks`, given as ids and
+/// names, to play on at once.
+fn combine_outputs_action(sinks: Vec<(OutputId, String)>) -> gtk::Box {
+    let action = gtk::Box::new(gtk::Orientation::Vertical, 5);
+    action.set_margin_top(5);
+
+    let button = gtk::Button::with_label("Combine outputs…");
+    let choices = gtk::Box::new(gtk::Orientation::Vertical, 0);
+    choices.set_no_show_all(true);
+
+    let checks: Rc<Vec<(gtk::CheckButton, OutputId, String)>> = Rc::new(
+        sinks
+            .into_iter()
+            .map(|(id, name)| (gtk::CheckButton::with_label(&name), id, name))
+            .collect(),
+    );
+    let combine = gtk::Button::with_label("Combine");
+    combine.set_sensitive(false);
+
+    for (check, _, _) in checks.iter() {
+        let (checks, combine) = (checks.clone(), combine.clone());
+        check.connect_toggled(move |_| {
+            let ticked = checks.iter().filter(|(check, _, _)| check.is_active());
+            combine.set_sensitive(ticked.count() > 1);
+        });
+        choices.add(check);
+    }
+    choices.add(&combine);
+
+    let checks_ = checks.clone();
+    combine.connect_clicked(move |_| {
+        handle_combine_outputs(
+            checks_
+                .iter()
+                .filter(|(check, _, _)| check.is_active())
+                .map(|(_, id, name)| (id.clone(), name.clone()))
+                .collect(),
+        );
+    });
+
+    let choices_ = choices.clone();
+    button.connect_clicked(move |button| {
+        button.hide();
+        choices_.set_no_show_all(false);
+        choices_.show_all();
+    });
+
+    action.add(&button);
+    action.add(&choices);
+    action
 }
 
 fn reposition_once_resized() {
@@ -337,7 +882,7 @@ fn remove_child_widgets(popout: &mut Popout) {
     });
 }
 
-fn handle_volume_slider_change(is_default: bool, vol: f32, id: String) {
+fn handle_volume_slider_change(vol: f32, id: OutputId) {
     let vol = clamp_volume_to_percent(vol);
 
     if (vol - shared_output_list::get_stored_volume(&id)).abs() < 2. {
@@ -346,18 +891,39 @@ fn handle_volume_slider_change(is_default: bool, vol: f32, id: String) {
 
     Popout::set_specific_volume_label(id.clone(), vol);
 
-    if is_default {
+    if shared_output_list::is_default_output(&id) {
         TrayIcon::set_volume(vol);
     }
-    Popout::set_ignore_next_callback();
+    Popout::set_ignore_next_callback(id.clone());
+
+    // The server keeps the channel ratios, so a balance change made before
+    // it reports back must start from the scaled channels.
+    let channels = shared_output_list::get_stored_channels(&id);
+    shared_output_list::set_stored_channels(
+        &id,
+        shared_output_list::scale_channels(&channels, vol),
+    );
 
-    let type_ = shared_output_list::type_of(&id);
-    AUDIO.lock().unwrap().aud.set_volume(id, vol, type_);
+    AUDIO.lock().unwrap().aud.set_volume(id, vol);
+}
+
+fn handle_balance_change(balance: f32, id: OutputId) {
+    let channels = shared_output_list::get_stored_channels(&id);
+
+    if (balance - shared_output_list::get_balance(&channels)).abs() < 0.01 {
+        return;
+    }
+
+    let channels = shared_output_list::set_balance(&channels, balance);
+    shared_output_list::set_stored_channels(&id, channels.clone());
+    Popout::set_ignore_next_callback(id.clone());
+
+    AUDIO.lock().unwrap().aud.set_channel_volumes(id, channels);
 }
 
 fn clamp_volume_to_percent(vol: f32) -> f32 {
-    if vol > 100. {
-        100.
+    if vol > OPTIONS.max_volume {
+        OPTIONS.max_volume
     } else if vol < 0. {
         0.
     } else {
@@ -365,13 +931,12 @@ fn clamp_volume_to_percent(vol: f32) -> f32 {
     }
 }
 
-fn handle_mute_button(id: String) {
-    let type_ = shared_output_list::type_of(&id);
+fn handle_mute_button(id: OutputId) {
+    Popout::set_ignore_next_callback(id.clone());
 
     let mut muted = false;
     {
         let mut list = shared_output_list::OUTPUT_LIST.lock().unwrap();
-        Popout::set_ignore_next_callback();
         for output in list.iter_mut() {
             if output.id == id {
                 muted = !output.muted;
@@ -386,7 +951,147 @@ fn handle_mute_button(id: String) {
 
     Popout::set_specific_muted(id.clone(), muted);
 
-    AUDIO.lock().unwrap().aud.set_muted(id, muted, type_);
+    AUDIO.lock().unwrap().aud.set_muted(id, muted);
+}
+
+fn handle_default_selected(id: OutputId) {
+    let name = id.name.clone().unwrap_or_default();
+
+    match id.type_ {
+        VolumeType::Sink => {
+            if shared_output_list::is_default_output(&id) {
+                return;
+            }
+            shared_output_list::set_default_output(name);
+
+            if let Ok(output) = shared_output_list::get_default_output() {
+                TrayIcon::set_muted(output.muted);
+                TrayIcon::set_volume(output.volume);
+            }
+        }
+        VolumeType::Input => {
+            if shared_output_list::is_default_input(&id) {
+                return;
+            }
+            shared_output_list::set_default_input(name);
+        }
+        VolumeType::Stream | VolumeType::Recording => return,
+    }
+
+    AUDIO.lock().unwrap().aud.set_default(id);
+}
+
+fn handle_port_selected(id: OutputId, port: String) {
+    if shared_output_list::get_active_port(&id).as_ref() == Some(&port) {
+        return;
+    }
+
+    shared_output_list::set_active_port(&id, port.clone());
+    Popout::set_specific_port(id.clone(), Some(port.clone()));
+
+    AUDIO.lock().unwrap().aud.set_port(id, port);
+}
+
+fn handle_profile_selected(card_id: String, profile: String) {
+    match shared_output_list::get_card(&card_id) {
+        Some(card) if card.active_profile.as_ref() != Some(&profile) => {}
+        // Already active, e.g. the chooser following a server update.
+        _ => return,
+    }
+
+    shared_output_list::set_active_profile(&card_id, &profile);
+
+    // The card's other devices show the same profile.
+    for output in shared_output_list::get_output_list() {
+        if output.card.as_ref().map(|card| &card.id) == Some(&card_id) {
+            Popout::set_specific_profile(output.id, Some(profile.clone()));
+        }
+    }
+
+    AUDIO.lock().unwrap().aud.set_card_profile(card_id, profile);
+}
+
+fn handle_new_virtual_device(name: String) {
+    let name = name.trim();
+    if name.is_empty() {
+        return;
+    }
+
+    AUDIO
+        .lock()
+        .unwrap()
+        .aud
+        .create_virtual_sink(name.to_string());
+}
+
+/// Combines `sinks`, given as ids and names, into one named after them.
+fn handle_combine_outputs(sinks: Vec<(OutputId, String)>) {
+    if sinks.len() < 2 {
+        return;
+    }
+
+    let name = sinks
+        .iter()
+        .map(|(_, name)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(" + ");
+    let sink_ids = sinks.into_iter().map(|(id, _)| id).collect();
+    AUDIO.lock().unwrap().aud.combine_sinks(name, sink_ids);
+}
+
+fn handle_listen_toggled(id: OutputId, listening: bool) {
+    {
+        let mut list = shared_output_list::OUTPUT_LIST.lock().unwrap();
+        // Updates from the server toggle the button too.
+        match list.iter_mut().find(|output| output.id == id) {
+            Some(output) if output.listening != listening => output.listening = listening,
+            _ => return,
+        }
+    }
+
+    AUDIO.lock().unwrap().aud.set_listening(id, listening);
+}
+
+fn handle_echo_cancel_toggled(id: OutputId, echo_cancelled: bool) {
+    {
+        let mut list = shared_output_list::OUTPUT_LIST.lock().unwrap();
+        // Updates from the server toggle the check box too.
+        match list.iter_mut().find(|output| output.id == id) {
+            Some(output) if output.echo_cancelled != echo_cancelled => {
+                output.echo_cancelled = echo_cancelled
+            }
+            _ => return,
+        }
+    }
+
+    Popout::set_specific_echo_cancelled(id.clone(), echo_cancelled);
+    AUDIO
+        .lock()
+        .unwrap()
+        .aud
+        .set_echo_cancelled(id, echo_cancelled);
+}
+
+fn handle_remove_virtual_device(id: OutputId) {
+    AUDIO.lock().unwrap().aud.remove_virtual_sink(id);
+}
+
+fn handle_stream_moved(stream_id: OutputId, sink_id: OutputId) {
+    {
+        let mut list = shared_output_list::OUTPUT_LIST.lock().unwrap();
+        match list.iter_mut().find(|output| output.id == stream_id) {
+            Some(stream) if stream.sink_id.as_ref() != Some(&sink_id) => {
+                stream.sink_id = Some(sink_id.clone());
+            }
+            // Already there, e.g. the chooser following a server update.
+            _ => return,
+        }
+    }
+
+    // Dropped rows leave the chooser behind.
+    Popout::set_specific_sink(stream_id.clone(), Some(sink_id.clone()));
+
+    AUDIO.lock().unwrap().aud.move_stream(stream_id, sink_id);
 }
 
 fn grab_seat(popout: &gtk::gdk::Window) {
@@ -414,3 +1119,579 @@ fn grab_seat(popout: &gtk::gdk::Window) {
 //     let seat = display.default_seat().unwrap();
 //     seat.ungrab();
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::mock::{output, stereo, Call, MOCK};
+    use crate::audio::Audio;
+    use crate::test_harness::{id, load, pump_events, row, run_on_gtk};
+
+    /// Loads `others` after the default output, "speakers" at 40%.
+    fn load_with_speakers(others: Vec<shared_output_list::Output>) {
+        let mut outputs = vec![output("speakers", VolumeType::Sink, 40., false)];
+        outputs.extend(others);
+        load(outputs, "speakers");
+    }
+
+    fn load_sinks() {
+        load_with_speakers(vec![output("headphones", VolumeType::Sink, 60., false)]);
+    }
+
+    fn monitor() -> shared_output_list::Output {
+        let mut monitor = output("speakers.monitor", VolumeType::Input, 100., false);
+        monitor.source_type = Some(SourceType::Monitor);
+        monitor
+    }
+
+    #[test]
+    fn slider_change_calls_backend() {
+        run_on_gtk(|| {
+            load_sinks();
+            assert_eq!(row("speakers").state, (40., false));
+            assert_eq!(row("headphones").state, (60., false));
+
+            handle_volume_slider_change(70., id("speakers"));
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetVolume("speakers".to_string(), 70.)]
+            );
+            assert_eq!(TrayIcon::state(), Some((70., false)));
+
+            handle_volume_slider_change(90., id("headphones"));
+            pump_events();
+
+            assert_eq!(MOCK.calls().len(), 2);
+            assert_eq!(TrayIcon::state(), Some((70., false)));
+        });
+    }
+
+    #[test]
+    fn object_events_only_ignore_their_own_echo() {
+        run_on_gtk(|| {
+            load_sinks();
+            handle_volume_slider_change(70., id("speakers"));
+
+            let mut handled = vec![];
+            for output_id in [id("headphones"), id("speakers"), id("speakers")] {
+                if !Popout::ignores_object_event(|echoed| *echoed == output_id) {
+                    handled.push(output_id);
+                }
+            }
+
+            assert_eq!(handled, vec![id("headphones"), id("speakers")]);
+        });
+    }
+
+    #[test]
+    fn small_slider_changes_are_ignored() {
+        run_on_gtk(|| {
+            load_sinks();
+
+            handle_volume_slider_change(41., id("speakers"));
+            pump_events();
+
+            assert!(MOCK.calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn echo_of_own_change_is_ignored() {
+        run_on_gtk(|| {
+            load_sinks();
+
+            handle_volume_slider_change(70., id("speakers"));
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(shared_output_list::get_stored_volume(&id("speakers")), 40.);
+
+            MOCK.update("speakers", |output| output.volume = 20.);
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("speakers").state, (20., false));
+            assert_eq!(TrayIcon::state(), Some((20., false)));
+        });
+    }
+
+    #[test]
+    fn selecting_default_retargets_tray() {
+        run_on_gtk(|| {
+            load_sinks();
+            assert_eq!(row("speakers").default_selected, Some(true));
+            assert_eq!(row("headphones").default_selected, Some(false));
+
+            handle_default_selected(id("headphones"));
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetDefault("headphones".to_string())]
+            );
+            assert_eq!(TrayIcon::state(), Some((60., false)));
+
+            handle_volume_slider_change(80., id("headphones"));
+            pump_events();
+
+            assert_eq!(TrayIcon::state(), Some((80., false)));
+        });
+    }
+
+    #[test]
+    fn external_default_change_updates_selectors() {
+        run_on_gtk(|| {
+            load_sinks();
+
+            shared_output_list::set_default_output("headphones".to_string());
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("headphones").default_selected, Some(true));
+            assert_eq!(row("speakers").default_selected, Some(false));
+            assert_eq!(TrayIcon::state(), Some((60., false)));
+            assert!(MOCK.calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn streams_can_be_moved() {
+        run_on_gtk(|| {
+            let speakers = output("speakers", VolumeType::Sink, 40., false);
+            let mut music = output("music", VolumeType::Stream, 50., false);
+            music.sink_id = Some(speakers.id.clone());
+            load(
+                vec![
+                    speakers,
+                    output("headphones", VolumeType::Sink, 60., false),
+                    music,
+                ],
+                "speakers",
+            );
+            assert_eq!(row("music").sink, Some("speakers".to_string()));
+
+            handle_stream_moved(id("music"), id("headphones"));
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::MoveStream(
+                    "music".to_string(),
+                    "headphones".to_string()
+                )]
+            );
+            assert_eq!(row("music").sink, Some("headphones".to_string()));
+
+            let speakers = id("speakers");
+            MOCK.update("music", |output| output.sink_id = Some(speakers));
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("music").sink, Some("speakers".to_string()));
+            assert_eq!(MOCK.calls().len(), 1);
+        });
+    }
+
+    #[test]
+    fn recordings_have_no_device_controls() {
+        run_on_gtk(|| {
+            load_with_speakers(vec![
+                output("mic", VolumeType::Input, 30., false),
+                output("obs", VolumeType::Recording, 70., false),
+            ]);
+            assert_eq!(row("obs").state, (70., false));
+            assert_eq!(row("obs").sink, None);
+            assert_eq!(row("obs").default_selected, None);
+
+            handle_mute_button(id("obs"));
+            pump_events();
+
+            assert_eq!(MOCK.calls(), vec![Call::SetMuted("obs".to_string(), true)]);
+            assert_eq!(row("obs").state, (70., true));
+        });
+    }
+
+    #[test]
+    fn peak_meters_appear_once_levels_arrive() {
+        run_on_gtk(|| {
+            load_sinks();
+            assert_eq!(row("speakers").peak, None);
+
+            Popout::set_specific_peak(id("speakers"), 0.25);
+            pump_events();
+
+            assert_eq!(row("speakers").peak, Some(0.25));
+            assert_eq!(row("headphones").peak, None);
+        });
+    }
+
+    #[test]
+    fn server_is_shown_when_known() {
+        run_on_gtk(|| {
+            load_sinks();
+            assert_eq!(Popout::server_info(), None);
+
+            shared_output_list::set_server_info(Some("pulseaudio 16.1 on studio".to_string()));
+            load_sinks();
+            shared_output_list::set_server_info(None);
+
+            assert_eq!(
+                Popout::server_info().as_deref(),
+                Some("pulseaudio 16.1 on studio")
+            );
+            assert_eq!(row("speakers").state, (40., false));
+        });
+    }
+
+    #[test]
+    fn reconnecting_replaces_the_rows() {
+        run_on_gtk(|| {
+            load_sinks();
+
+            Popout::show_reconnecting();
+            TrayIcon::set_connected(false);
+            pump_events();
+
+            assert_eq!(Popout::row("speakers"), None);
+            assert_eq!(TrayIcon::is_connected(), Some(false));
+
+            TrayIcon::set_connected(true);
+            load_sinks();
+
+            assert_eq!(row("speakers").state, (40., false));
+            assert_eq!(TrayIcon::is_connected(), Some(true));
+        });
+    }
+
+    #[test]
+    fn inputs_show_their_class() {
+        run_on_gtk(|| {
+            let mut mic = output("mic", VolumeType::Input, 30., false);
+            mic.source_type = Some(SourceType::Hardware);
+            load_with_speakers(vec![mic, monitor()]);
+
+            assert_eq!(row("mic").caption, Some("Hardware".to_string()));
+            assert_eq!(row("speakers.monitor").caption, Some("Monitor".to_string()));
+            assert_eq!(row("speakers").caption, None);
+
+            handle_default_selected(id("speakers.monitor"));
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetDefault("speakers.monitor".to_string())]
+            );
+        });
+    }
+
+    #[test]
+    fn balance_follows_channels() {
+        run_on_gtk(|| {
+            let mut speakers = output("speakers", VolumeType::Sink, 60., false);
+            speakers.channels = stereo(60., 60.);
+            load(
+                vec![speakers, output("mic", VolumeType::Input, 30., false)],
+                "speakers",
+            );
+            assert_eq!(row("speakers").balance, Some(0.));
+            assert_eq!(row("mic").balance, None);
+
+            handle_balance_change(-0.5, id("speakers"));
+            // The server's echo of the change is ignored.
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetChannelVolumes(
+                    "speakers".to_string(),
+                    stereo(60., 30.)
+                )]
+            );
+
+            MOCK.update("speakers", |output| {
+                output.volume = 40.;
+                output.channels = stereo(20., 40.);
+            });
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("speakers").balance, Some(0.5));
+            assert_eq!(row("speakers").state, (40., false));
+        });
+    }
+
+    #[test]
+    fn ports() {
+        run_on_gtk(|| {
+            let port = |name: &str, available| shared_output_list::Port {
+                name: name.to_string(),
+                description: name.to_string(),
+                available,
+            };
+            let mut speakers = output("speakers", VolumeType::Sink, 40., false);
+            speakers.ports = vec![port("lineout", None), port("headphones", Some(false))];
+            speakers.active_port = Some("lineout".to_string());
+            load(vec![speakers], "speakers");
+
+            assert_eq!(row("speakers").port, Some("lineout".to_string()));
+
+            // Plugging in headphones switches to them.
+            MOCK.update("speakers", |output| {
+                output.ports[1].available = Some(true);
+                output.active_port = Some("headphones".to_string());
+            });
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("speakers").port, Some("headphones".to_string()));
+
+            handle_port_selected(id("speakers"), "lineout".to_string());
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetPort("speakers".to_string(), "lineout".to_string())]
+            );
+
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("speakers").port, Some("lineout".to_string()));
+        });
+    }
+
+    #[test]
+    fn card_profiles() {
+        run_on_gtk(|| {
+            let card = shared_output_list::Card {
+                id: "pci".to_string(),
+                pa_index: None,
+                profiles: vec![
+                    ("analog".to_string(), "Analog Stereo".to_string()),
+                    ("hdmi".to_string(), "HDMI".to_string()),
+                ],
+                active_profile: Some("analog".to_string()),
+            };
+            let mut speakers = output("speakers", VolumeType::Sink, 40., false);
+            speakers.card = Some(card.clone());
+            let mut mic = output("mic", VolumeType::Input, 40., false);
+            mic.card = Some(card);
+            load(vec![speakers, mic], "speakers");
+
+            assert_eq!(row("mic").profile, Some("analog".to_string()));
+
+            handle_profile_selected("pci".to_string(), "hdmi".to_string());
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetCardProfile("pci".to_string(), "hdmi".to_string())]
+            );
+            assert_eq!(row("speakers").profile, Some("hdmi".to_string()));
+            assert_eq!(row("mic").profile, Some("hdmi".to_string()));
+
+            MOCK.update("speakers", |output| {
+                output.card.as_mut().unwrap().active_profile = Some("analog".to_string())
+            });
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("speakers").profile, Some("analog".to_string()));
+            assert_eq!(MOCK.calls().len(), 1);
+        });
+    }
+
+    #[test]
+    fn virtual_devices_can_be_created_and_removed() {
+        run_on_gtk(|| {
+            load_sinks();
+            assert!(!row("speakers").removable);
+
+            handle_new_virtual_device("  ".to_string());
+            handle_new_virtual_device(" Stream mix ".to_string());
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::CreateVirtualSink("Stream mix".to_string())]
+            );
+            assert!(row("Stream mix").removable);
+            assert_eq!(row("Stream mix").caption.as_deref(), Some("Virtual"));
+            assert_eq!(MOCK.created_devices(), vec!["Stream mix".to_string()]);
+
+            handle_remove_virtual_device(id("Stream mix"));
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(Popout::row("Stream mix"), None);
+            assert!(MOCK.created_devices().is_empty());
+        });
+    }
+
+    #[test]
+    fn outputs_can_be_combined() {
+        run_on_gtk(|| {
+            load_sinks();
+
+            handle_combine_outputs(vec![(id("speakers"), "speakers".to_string())]);
+            handle_combine_outputs(vec![
+                (id("speakers"), "speakers".to_string()),
+                (id("headphones"), "headphones".to_string()),
+            ]);
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::CombineSinks(
+                    "speakers + headphones".to_string(),
+                    vec!["speakers".to_string(), "headphones".to_string()]
+                )]
+            );
+            assert_eq!(
+                row("speakers + headphones").caption.as_deref(),
+                Some("Plays on speakers, headphones")
+            );
+            assert!(row("speakers + headphones").removable);
+
+            handle_remove_virtual_device(id("speakers + headphones"));
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(Popout::row("speakers + headphones"), None);
+            assert_eq!(row("speakers").state, (40., false));
+        });
+    }
+
+    #[test]
+    fn inputs_can_be_monitored() {
+        run_on_gtk(|| {
+            load_with_speakers(vec![
+                output("mic", VolumeType::Input, 30., false),
+                monitor(),
+            ]);
+
+            assert_eq!(row("mic").listening, Some(false));
+            assert_eq!(row("speakers").listening, None);
+            assert_eq!(row("speakers.monitor").listening, None);
+
+            handle_listen_toggled(id("mic"), true);
+            handle_listen_toggled(id("mic"), true);
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetListening("mic".to_string(), true)]
+            );
+
+            // Another client stopping it is shown without a call back.
+            MOCK.update("mic", |mic| mic.listening = false);
+            MOCK.emit_change();
+            pump_events();
+
+            assert_eq!(row("mic").listening, Some(false));
+            assert_eq!(MOCK.calls().len(), 1);
+        });
+    }
+
+    #[test]
+    fn stream_labels_follow_what_is_playing() {
+        run_on_gtk(|| {
+            let mut stream = output("firefox", VolumeType::Stream, 50., false);
+            stream.name = "Firefox".to_string();
+            stream.detail = Some("YouTube: a song title that goes on and on".to_string());
+            load_with_speakers(vec![stream]);
+
+            assert_eq!(row("firefox").name, Some(("Firefox".to_string(), None)));
+            assert_eq!(
+                row("firefox").caption.as_deref(),
+                Some("YouTube: a song title that goe…")
+            );
+
+            MOCK.update("firefox", |stream| {
+                stream.detail = Some("YouTube: the next song".to_string())
+            });
+            MOCK.emit_change();
+            pump_events();
+            assert_eq!(
+                row("firefox").caption.as_deref(),
+                Some("YouTube: the next song")
+            );
+
+            MOCK.update("firefox", |stream| stream.detail = None);
+            MOCK.emit_change();
+            pump_events();
+            assert_eq!(row("firefox").caption, None);
+        });
+    }
+
+    #[test]
+    fn long_names_have_a_tooltip() {
+        run_on_gtk(|| {
+            let name = "Built-in Audio Analog Stereo Output";
+            let mut speakers = output("speakers", VolumeType::Sink, 40., false);
+            speakers.name = name.to_string();
+            load(vec![speakers], "speakers");
+
+            assert_eq!(
+                row("speakers").name,
+                Some((
+                    "Built-in Audio Analog Stereo O…".to_string(),
+                    Some(name.to_string())
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn echo_cancellation_shows_a_badge() {
+        run_on_gtk(|| {
+            load_with_speakers(vec![output("mic", VolumeType::Input, 30., false)]);
+
+            assert_eq!(row("mic").echo_cancelled, Some(false));
+            assert_eq!(row("speakers").echo_cancelled, None);
+
+            handle_echo_cancel_toggled(id("mic"), true);
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetEchoCancelled("mic".to_string(), true)]
+            );
+            assert_eq!(row("mic").echo_cancelled, Some(true));
+
+            handle_echo_cancel_toggled(id("mic"), false);
+            pump_events();
+
+            assert_eq!(row("mic").echo_cancelled, Some(false));
+            assert_eq!(MOCK.calls().len(), 2);
+        });
+    }
+
+    #[test]
+    fn mute_button_toggles() {
+        run_on_gtk(|| {
+            load_sinks();
+
+            handle_mute_button(id("speakers"));
+            pump_events();
+
+            assert_eq!(
+                MOCK.calls(),
+                vec![Call::SetMuted("speakers".to_string(), true)]
+            );
+            assert_eq!(row("speakers").state, (40., true));
+            assert_eq!(TrayIcon::state(), Some((40., true)));
+
+            handle_mute_button(id("speakers"));
+            pump_events();
+
+            assert_eq!(row("speakers").state, (40., false));
+            assert_eq!(TrayIcon::state(), Some((40., false)));
+        });
+    }
+}
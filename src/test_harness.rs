@@ -0,0 +1,128 @@
+//! Runs UI tests against the mock backend on a single GTK thread.
+//!
+//! GTK needs a display, so these tests are meant to run under Xvfb, e.g.
+//! `xvfb-run cargo test`. Without a display they fail rather than pass
+//! without running.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Mutex, MutexGuard},
+    thread,
+};
+
+use gtk::{gio::ApplicationFlags, prelude::*, Application};
+use once_cell::sync::Lazy;
+
+use crate::{
+    audio::{
+        mock::MOCK,
+        reload_outputs_in_popout,
+        shared_output_list::{self, OutputId},
+    },
+    elements::RowState,
+    popout::Popout,
+    tray_icon::TrayIcon,
+    AUDIO,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+type JobResult = thread::Result<()>;
+type JobSender = mpsc::Sender<(Job, mpsc::Sender<JobResult>)>;
+
+/// Tests touching the global output list must hold this.
+pub static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+static GTK_THREAD: Lazy<Option<Mutex<JobSender>>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel::<(Job, mpsc::Sender<JobResult>)>();
+    let (ready_sender, ready_receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        if gtk::init().is_err() {
+            let _ = ready_sender.send(false);
+            return;
+        }
+
+        let app = Application::builder()
+            .application_id("com.github.jaspwr.vol-applet.tests")
+            .flags(ApplicationFlags::NON_UNIQUE)
+            .build();
+        let _ = app.register(gtk::gio::Cancellable::NONE);
+
+        Popout::initialise(&app);
+        TrayIcon::initialise();
+        let _ = ready_sender.send(true);
+
+        for (job, result) in receiver {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(job));
+            let _ = result.send(outcome);
+        }
+    });
+
+    if ready_receiver.recv().unwrap_or(false) {
+        Some(Mutex::new(sender))
+    } else {
+        None
+    }
+});
+
+pub fn lock_state() -> MutexGuard<'static, ()> {
+    STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs `f` on the GTK thread with the popout and tray icon initialised.
+pub fn run_on_gtk<F: FnOnce() + Send + 'static>(f: F) {
+    let _guard = lock_state();
+
+    let sender = GTK_THREAD
+        .as_ref()
+        .expect("No display available. Run the UI tests under Xvfb, e.g. `xvfb-run cargo test`.");
+
+    let (result_sender, result_receiver) = mpsc::channel();
+    sender
+        .lock()
+        .unwrap()
+        .send((Box::new(f), result_sender))
+        .unwrap();
+
+    if let Err(e) = result_receiver.recv().unwrap() {
+        panic::resume_unwind(e);
+    }
+}
+
+/// Runs pending idle callbacks, which is where the popout and tray icon
+/// apply updates.
+pub fn pump_events() {
+    while gtk::events_pending() {
+        gtk::main_iteration_do(false);
+    }
+}
+
+/// Scripts the mock backend and loads its outputs into the popout.
+pub fn load(outputs: Vec<shared_output_list::Output>, default_output_id: &str) {
+    // Drop any ignore flag left over from a previous test.
+    Popout::handle_callback(|_| {});
+
+    MOCK.script(outputs);
+    shared_output_list::set_default_output(default_output_id.to_string());
+
+    AUDIO
+        .lock()
+        .unwrap()
+        .aud
+        .get_outputs(Box::new(reload_outputs_in_popout));
+    pump_events();
+}
+
+/// The id of a loaded output, by name or by key for unnamed ones.
+pub fn id(key: &str) -> OutputId {
+    shared_output_list::get_output_list()
+        .into_iter()
+        .find(|output| output.id.name.as_deref() == Some(key) || output.id.to_string() == key)
+        .map(|output| output.id)
+        .unwrap_or_else(|| panic!("no output {}", key))
+}
+
+/// What an output's row shows, by name or by key for unnamed ones.
+pub fn row(key: &str) -> RowState {
+    Popout::row(key).unwrap_or_else(|| panic!("no row for {}", key))
+}
@@ -10,7 +10,14 @@ use gtk::{
 };
 use gtk_sys::*;
 
-use crate::{ exception::Exception, popout::Popout, audio::shared_output_list, AUDIO, elements::Percentise };
+use crate::{
+    exception::Exception,
+    popout::Popout,
+    audio::shared_output_list,
+    AUDIO,
+    elements::Percentise,
+    options::OPTIONS,
+};
 
 static TRAY_ICON: Mutex<Option<TrayIcon>> = Mutex::new(None);
 
@@ -19,6 +26,8 @@ pub struct TrayIcon {
     level: VolumeLevel,
     volume: f32,
     muted: bool,
+    /// Whether the backend is connected to the sound server.
+    connected: bool,
 }
 unsafe impl Sync for TrayIcon {}
 unsafe impl Send for TrayIcon {}
@@ -84,9 +93,12 @@ impl TrayIcon {
     }
 
     pub fn set_tooltip_volume(volume: f32) {
+        Self::set_tooltip(volume.format_volume());
+    }
+
+    fn set_tooltip(tooltip: String) {
         idle_add_once(move || {
             if let Some(icon) = TRAY_ICON.lock().unwrap().as_mut() {
-                let tooltip = volume.format_volume();
                 unsafe {
                     gtk_status_icon_set_tooltip_text(icon.icon_ptr, tooltip.as_str().to_glib_none().0);
                 }
@@ -95,13 +107,19 @@ impl TrayIcon {
     }
 
     fn set_volume_icon_level(&mut self, volume: f32, muted: bool) -> Result<(), Exception> {
-        let new_lvl = VolumeLevel::from_volume(volume, muted);
-        Self::set_tooltip_volume(volume);
+        let new_lvl = if self.connected {
+            Self::set_tooltip_volume(volume);
+            VolumeLevel::from_volume(volume, muted)
+        } else {
+            Self::set_tooltip("Disconnected".to_string());
+            VolumeLevel::Disconnected
+        };
         if self.level == new_lvl {
             return Ok(());
         }
         self.level = new_lvl;
-        match Self::fetch_icon(self.level.to_icon()) {
+        // Not every icon theme has an over-amplified or disconnected icon.
+        match Self::fetch_icon(self.level.to_icon()).or_else(|| Self::fetch_icon(self.level.fallback_icon())) {
             Some(icon_pix) => {
                 self.set_icon(icon_pix);
                 Ok(())
@@ -139,12 +157,37 @@ impl TrayIcon {
         TrayIcon::set_volume(vol);
     }
 
+    /// Shows a disconnected icon while the backend reconnects.
+    pub fn set_connected(connected: bool) {
+        let mut vol = 0.;
+        if let Some(icon) = TRAY_ICON.lock().unwrap().as_mut() {
+            icon.connected = connected;
+            vol = icon.volume;
+        }
+        TrayIcon::set_volume(vol);
+    }
+
+    #[cfg(test)]
+    pub fn state() -> Option<(f32, bool)> {
+        TRAY_ICON
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|icon| (icon.volume, icon.muted))
+    }
+
+    #[cfg(test)]
+    pub fn is_connected() -> Option<bool> {
+        TRAY_ICON.lock().unwrap().as_ref().map(|icon| icon.connected)
+    }
+
     pub fn initialise() {
         let mut tray_icon = Self {
             icon_ptr: std::ptr::null_mut(),
             level: VolumeLevel::High,
             volume: 0.,
             muted: false,
+            connected: true,
         };
         tray_icon.create_icon();
         TRAY_ICON.lock().unwrap().replace(tray_icon);
@@ -153,10 +196,12 @@ impl TrayIcon {
 
 #[derive(PartialEq)]
 enum VolumeLevel {
+    Overamplified,
     High,
     Medium,
     Low,
     Muted,
+    Disconnected,
 }
 
 impl VolumeLevel {
@@ -165,7 +210,12 @@ impl VolumeLevel {
             return VolumeLevel::Muted;
         }
 
-        if volume > 66. {
+        // The thresholds are slider positions, so they follow the scale.
+        let volume = OPTIONS.volume_scale.to_slider(volume);
+
+        if volume > 100. {
+            VolumeLevel::Overamplified
+        } else if volume > 66. {
             VolumeLevel::High
         } else if volume > 33. {
             VolumeLevel::Medium
@@ -176,10 +226,19 @@ impl VolumeLevel {
 
     fn to_icon(&self) -> &'static str {
         match self {
+            VolumeLevel::Overamplified => VOLUME_OVERAMPLIFIED,
             VolumeLevel::High => VOLUME_HIGH,
             VolumeLevel::Medium => VOLUME_MEDIUM,
             VolumeLevel::Low => VOLUME_LOW,
             VolumeLevel::Muted => VOLUME_MUTED,
+            VolumeLevel::Disconnected => VOLUME_DISCONNECTED,
+        }
+    }
+
+    fn fallback_icon(&self) -> &'static str {
+        match self {
+            VolumeLevel::Disconnected => VOLUME_MUTED,
+            _ => VOLUME_HIGH,
         }
     }
 }
@@ -210,10 +269,12 @@ unsafe fn g_signal_connect(
     )
 }
 
+static VOLUME_OVERAMPLIFIED: &str = "audio-volume-overamplified-symbolic";
 static VOLUME_HIGH: &str = "audio-volume-high-symbolic";
 static VOLUME_MEDIUM: &str = "audio-volume-medium-symbolic";
 static VOLUME_LOW: &str = "audio-volume-low-symbolic";
 static VOLUME_MUTED: &str = "audio-volume-muted-symbolic";
+static VOLUME_DISCONNECTED: &str = "audio-volume-muted-blocking-symbolic";
 
 trait DefaultRect {
     fn default() -> Self;